@@ -0,0 +1,35 @@
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Track deaths, the default.
+    Run,
+    // Print the characters in the save file and exit.
+    Slots,
+}
+
+impl Default for Command {
+    fn default() -> Command {
+        return Command::Run;
+    }
+}
+
+// Command line flags, these override the matching settings in config.toml.
+#[derive(Default)]
+pub struct Args {
+    pub command: Command,
+    pub profile: Option<String>,
+}
+
+pub fn parse() -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut raw = std::env::args().skip(1);
+    while let Some(arg) = raw.next() {
+        match arg.as_str() {
+            "--profile" => {
+                args.profile = Some(raw.next().ok_or("--profile needs a profile id")?);
+            },
+            "slots" => args.command = Command::Slots,
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    return Ok(args);
+}
@@ -0,0 +1,173 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+// Every file we write goes through here. Writing to a temporary file next to the real one and
+// renaming it over the top means anything reading the file, like an OBS text source, sees either
+// the old contents or the new ones, never a half written or empty file.
+
+// How hard to try to get a write onto the disk before carrying on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    // Leave it to the operating system, fine for overlays.
+    None,
+    // Flush the file's contents to disk before it replaces the old one.
+    File,
+    // Also flush the folder, so the rename itself survives a power cut.
+    Full,
+}
+
+impl Default for SyncMode {
+    fn default() -> SyncMode {
+        return SyncMode::None;
+    }
+}
+
+// Windows won't replace a file another program has open, which happens when OBS reads it at the
+// wrong moment, so the rename gets a few tries before giving up.
+const RENAME_ATTEMPTS: u32 = 5;
+const RENAME_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+pub fn write(path: &Path, contents: &[u8], sync: SyncMode) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let result = write_temp(&temp_path, contents, sync).and_then(|_| replace(&temp_path, path));
+    // Writing over the file instead would empty it first, which is what the rename is there to avoid.
+    // Leaving the old contents is better, the next change will try again.
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+        return result;
+    }
+    if sync == SyncMode::Full {
+        sync_folder(path);
+    }
+    return result;
+}
+
+// Appending can't go through a temporary file without copying the whole file every time, so the
+// line goes out in a single write instead, which is enough to stop anything reading it from
+// seeing half a line. The header is written first when the file is new or empty.
+pub fn append(path: &Path, header: &str, line: &str, sync: SyncMode) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut contents = String::new();
+    if file.metadata()?.len() == 0 {
+        contents.push_str(header);
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    file.write_all(contents.as_bytes())?;
+    if sync != SyncMode::None {
+        file.sync_all()?;
+    }
+    if sync == SyncMode::Full {
+        sync_folder(path);
+    }
+    return Ok(());
+}
+
+// In the same folder, since a rename can't move a file between drives.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    return path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+}
+
+fn write_temp(temp_path: &Path, contents: &[u8], sync: SyncMode) -> io::Result<()> {
+    let mut file = File::create(temp_path)?;
+    file.write_all(contents)?;
+    if sync != SyncMode::None {
+        file.sync_all()?;
+    }
+    return Ok(());
+}
+
+fn replace(temp_path: &Path, path: &Path) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match std::fs::rename(temp_path, path) {
+            Err(_) if attempt < RENAME_ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(RENAME_RETRY_DELAY);
+            },
+            result => return result,
+        }
+    }
+}
+
+// An empty folder of its own for a test to write files in.
+#[cfg(test)]
+pub fn test_folder(name: &str) -> PathBuf {
+    let folder = std::env::temp_dir().join(format!("souls-like-death-counter-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir_all(&folder).unwrap();
+    return folder;
+}
+
+// Only possible on Unix, Windows doesn't let you open a folder like a file.
+#[cfg(unix)]
+fn sync_folder(path: &Path) {
+    let folder = match path.parent() {
+        Some(folder) if folder != Path::new("") => folder,
+        _ => Path::new("."),
+    };
+    if let Ok(folder) = File::open(folder) {
+        let _ = folder.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_folder(_path: &Path) {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_replaces_the_contents() {
+        let folder = test_folder("write-replaces");
+        let path = folder.join("deaths.txt");
+        write(&path, b"12345", SyncMode::None).unwrap();
+        write(&path, b"6", SyncMode::Full).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"6");
+        assert_eq!(std::fs::read_dir(&folder).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn failed_replace_keeps_the_old_contents() {
+        let folder = test_folder("failed-replace");
+        // A rename can't replace a folder with something in it, which stands in for a file another
+        // program has locked.
+        let path = folder.join("deaths.txt");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("old"), b"12").unwrap();
+        assert!(write(&path, b"13", SyncMode::None).is_err());
+        assert_eq!(std::fs::read(path.join("old")).unwrap(), b"12");
+        // Nothing left behind but the original.
+        let names: Vec<_> = std::fs::read_dir(&folder).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, vec![std::ffi::OsString::from("deaths.txt")]);
+    }
+
+    #[test]
+    fn append_writes_the_header_when_the_file_is_empty() {
+        let folder = test_folder("append-header");
+        let path = folder.join("deaths.csv");
+        append(&path, "a,b", "1,2", SyncMode::None).unwrap();
+        append(&path, "a,b", "3,4", SyncMode::File).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\n1,2\n3,4\n");
+
+        // An empty file gets the header, one with lines already in it doesn't.
+        let empty = folder.join("empty.csv");
+        std::fs::write(&empty, b"").unwrap();
+        append(&empty, "a,b", "1,2", SyncMode::None).unwrap();
+        assert_eq!(std::fs::read_to_string(&empty).unwrap(), "a,b\n1,2\n");
+        let existing = folder.join("existing.csv");
+        std::fs::write(&existing, b"5,6\n").unwrap();
+        append(&existing, "a,b", "1,2", SyncMode::None).unwrap();
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "5,6\n1,2\n");
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use crate::games::SlotInfo;
+
+// Follows whichever slot the game last saved, for save_slot = "auto".
+// Games only rewrite the slot that's being played, so the slot whose data changed
+// since the last read is the character on screen.
+#[derive(Default)]
+pub struct AutoSlot {
+    current: Option<usize>,
+    fingerprints: HashMap<usize, u64>,
+}
+
+impl AutoSlot {
+    pub fn new() -> AutoSlot {
+        return AutoSlot::default();
+    }
+
+    // Returns the slot to read deaths from, None if the save has no characters.
+    pub fn update(&mut self, slots: &[SlotInfo]) -> Option<usize> {
+        let changed: Vec<usize> = slots.iter()
+            .filter(|info| self.fingerprints.get(&info.slot) != Some(&info.fingerprint))
+            .map(|info| info.slot)
+            .collect();
+        let first_read = self.fingerprints.is_empty();
+        self.fingerprints = slots.iter().map(|info| (info.slot, info.fingerprint)).collect();
+
+        let next = if first_read {
+            // Nothing to compare against yet, so start with the most played character. Only ELDEN RING
+            // gives us play time, for the other games every slot ties and the lowest numbered one wins.
+            slots.iter()
+                .max_by_key(|info| (info.play_time, std::cmp::Reverse(info.slot)))
+                .map(|info| info.slot)
+        } else if self.current.is_some_and(|slot| changed.contains(&slot)) {
+            self.current
+        } else if let Some(slot) = changed.first() {
+            Some(*slot)
+        } else {
+            // Nothing changed, keep following the same character unless it was deleted.
+            self.current.filter(|slot| slots.iter().any(|info| info.slot == *slot))
+                .or_else(|| slots.first().map(|info| info.slot))
+        };
+
+        if next != self.current {
+            if let Some(slot) = next {
+                let name = slots.iter().find(|info| info.slot == slot).and_then(|info| info.name.clone());
+                match name {
+                    Some(name) => println!("Auto slot: now tracking slot {} ({})", slot, name),
+                    None => println!("Auto slot: now tracking slot {}", slot),
+                }
+            }
+            self.current = next;
+        }
+        return next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn slot(slot: usize, fingerprint: u64, hours: Option<u64>) -> SlotInfo {
+        return SlotInfo {
+            slot,
+            name: None,
+            level: None,
+            play_time: hours.map(|hours| Duration::from_secs(hours * 3600)),
+            deaths: 0,
+            fingerprint,
+        };
+    }
+
+    #[test]
+    fn first_read_picks_the_most_played_slot() {
+        let mut auto_slot = AutoSlot::new();
+        assert_eq!(auto_slot.update(&[slot(0, 1, Some(2)), slot(3, 1, Some(9)), slot(5, 1, Some(4))]), Some(3));
+    }
+
+    #[test]
+    fn first_read_without_play_time_picks_the_lowest_slot() {
+        let mut auto_slot = AutoSlot::new();
+        assert_eq!(auto_slot.update(&[slot(2, 1, None), slot(4, 1, None), slot(7, 1, None)]), Some(2));
+        assert_eq!(AutoSlot::new().update(&[]), None);
+    }
+
+    #[test]
+    fn switches_to_the_slot_that_changed() {
+        let mut auto_slot = AutoSlot::new();
+        auto_slot.update(&[slot(0, 1, None), slot(1, 1, None)]);
+        assert_eq!(auto_slot.update(&[slot(0, 1, None), slot(1, 2, None)]), Some(1));
+        // Back to the first character.
+        assert_eq!(auto_slot.update(&[slot(0, 2, None), slot(1, 2, None)]), Some(0));
+    }
+
+    #[test]
+    fn stays_on_the_slot_when_nothing_changed() {
+        let mut auto_slot = AutoSlot::new();
+        auto_slot.update(&[slot(0, 1, None), slot(1, 1, None)]);
+        assert_eq!(auto_slot.update(&[slot(0, 1, None), slot(1, 2, None)]), Some(1));
+        assert_eq!(auto_slot.update(&[slot(0, 1, None), slot(1, 2, None)]), Some(1));
+        // The tracked slot changing along with another one keeps it tracked.
+        assert_eq!(auto_slot.update(&[slot(0, 3, None), slot(1, 3, None)]), Some(1));
+    }
+
+    #[test]
+    fn deleted_slot_falls_back_to_the_first_slot() {
+        let mut auto_slot = AutoSlot::new();
+        auto_slot.update(&[slot(0, 1, None), slot(1, 1, None), slot(2, 1, None)]);
+        assert_eq!(auto_slot.update(&[slot(0, 1, None), slot(1, 1, None), slot(2, 2, None)]), Some(2));
+        assert_eq!(auto_slot.update(&[slot(0, 1, None), slot(1, 1, None)]), Some(0));
+    }
+}
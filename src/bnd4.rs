@@ -0,0 +1,277 @@
+use crate::bytes::{check_range, read_u32, read_u64, slice};
+use crate::error::SaveError;
+
+// BND4 is the container format used by the DS2, DS3, Sekiro and ELDEN RING save files.
+// It is a small header, followed by a table of entry headers, followed by the entry names and data.
+//
+// Header layout (0x40 bytes)
+// Magic "BND4" (4 bytes)
+// --unused-- (8 Bytes)
+// Entry Count (4 Bytes)
+// Header Size (8 Bytes)
+// Version (8 Bytes, e.g. "00000001")
+// Entry Header Size (8 Bytes)
+// Data Start (8 Bytes)
+// Unicode Names (1 Byte)
+// Format (1 Byte)
+// --unused-- (14 Bytes)
+//
+// Entry header layout (0x20 bytes in every save file seen so far)
+// Flags (4 bytes)
+// --unused-- (4 Bytes, always 0xFFFFFFFF)
+// Data Length (8 Bytes)
+// Data Offset (4 Bytes)
+// Name Offset (4 Bytes)
+// --unused-- (8 Bytes)
+
+const MAGIC: &[u8; 4] = b"BND4";
+const HEADER_SIZE: usize = 0x40;
+// Anything smaller and the entry header wouldn't reach the name offset.
+const MIN_ENTRY_HEADER_SIZE: usize = 0x18;
+// Names are short ("USER_DATA000"), anything longer means the offset is pointing into garbage.
+const MAX_NAME_LENGTH: usize = 0x100;
+
+// Not everything here is needed to find the saves' entries, it's all kept so the whole format is
+// described in one place and anyone looking into a new save can see what's in it.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Header {
+    pub entry_count: usize,
+    pub version: String,
+    pub entry_header_size: usize,
+    pub data_start: usize,
+    // Whether the entry names are UTF-16, they're single byte otherwise.
+    pub unicode: bool,
+    pub format: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Entry {
+    pub index: usize,
+    pub flags: u32,
+    pub data_length: usize,
+    pub data_offset: usize,
+    pub name_offset: usize,
+    pub name: String,
+}
+
+pub struct Bnd4<'a> {
+    buffer: &'a [u8],
+    #[allow(dead_code)]
+    pub header: Header,
+    entries: Vec<Entry>,
+}
+
+impl<'a> Bnd4<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Result<Bnd4<'a>, SaveError> {
+        if buffer.len() < HEADER_SIZE {
+            return Err(SaveError::Truncated { offset: 0, length: HEADER_SIZE });
+        }
+        if &buffer[0..4] != MAGIC {
+            return Err(SaveError::BadMagic);
+        }
+
+        let header = Header {
+            entry_count: read_u32(buffer, 0x0C)? as usize,
+            version: String::from_utf8_lossy(&buffer[0x18..0x20]).trim_end_matches('\0').to_string(),
+            entry_header_size: read_u64(buffer, 0x20)? as usize,
+            data_start: read_u64(buffer, 0x28)? as usize,
+            unicode: buffer[0x30] != 0,
+            format: buffer[0x31],
+        };
+        if header.entry_header_size < MIN_ENTRY_HEADER_SIZE {
+            return Err(SaveError::BadMagic);
+        }
+        // Check the whole table fits before allocating anything based on the entry count.
+        let table_length = header.entry_count.checked_mul(header.entry_header_size)
+            .ok_or(SaveError::Truncated { offset: HEADER_SIZE, length: usize::MAX })?;
+        check_range(buffer, HEADER_SIZE, table_length)?;
+
+        let mut entries = Vec::with_capacity(header.entry_count);
+        for index in 0..header.entry_count {
+            let entry_start = HEADER_SIZE + index * header.entry_header_size;
+            let data_length = read_u64(buffer, entry_start + 8)? as usize;
+            let data_offset = read_u32(buffer, entry_start + 16)? as usize;
+            let name_offset = read_u32(buffer, entry_start + 20)? as usize;
+            check_range(buffer, data_offset, data_length)?;
+            entries.push(Entry {
+                index,
+                flags: read_u32(buffer, entry_start)?,
+                data_length,
+                data_offset,
+                name_offset,
+                name: read_name(buffer, name_offset, header.unicode)?,
+            });
+        }
+
+        return Ok(Bnd4 {
+            buffer,
+            header,
+            entries,
+        });
+    }
+
+    pub fn entries(&self) -> std::slice::Iter<'_, Entry> {
+        return self.entries.iter();
+    }
+
+    pub fn find(&self, name: &str) -> Result<&Entry, SaveError> {
+        return self.entries().find(|entry| entry.name == name)
+            .ok_or_else(|| SaveError::MissingEntry(name.to_string()));
+    }
+
+    // Every entry was bounds checked in parse, so slicing here can't go out of range.
+    pub fn data(&self, entry: &Entry) -> &'a [u8] {
+        return &self.buffer[entry.data_offset..entry.data_offset + entry.data_length];
+    }
+}
+
+// The saves name their entries USER_DATA000, USER_DATA001 and so on.
+pub fn user_data_name(number: usize) -> String {
+    return format!("USER_DATA{:03}", number);
+}
+
+// Names are null terminated, UTF-16 when the header's unicode flag is set and single byte otherwise.
+fn read_name(buffer: &[u8], offset: usize, unicode: bool) -> Result<String, SaveError> {
+    let char_size = if unicode { 2 } else { 1 };
+    let mut units = Vec::new();
+    loop {
+        if units.len() >= MAX_NAME_LENGTH {
+            return Err(SaveError::UnexpectedData { offset });
+        }
+        let position = offset.saturating_add(units.len() * char_size);
+        let unit = match slice(buffer, position, char_size)? {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            bytes => bytes[0] as u16,
+        };
+        if unit == 0 {
+            break;
+        }
+        units.push(unit);
+    }
+    return Ok(String::from_utf16_lossy(&units));
+}
+
+// Wraps entries up the way the games do, for building save files in tests.
+// The entries are named with user_data_name, in order.
+#[cfg(test)]
+pub fn build(entries: &[Vec<u8>]) -> Vec<u8> {
+    const ENTRY_HEADER_SIZE: usize = 0x20;
+    let names_start = HEADER_SIZE + entries.len() * ENTRY_HEADER_SIZE;
+    let names: Vec<Vec<u8>> = (0..entries.len())
+        .map(|index| user_data_name(index).encode_utf16().chain(Some(0)).flat_map(u16::to_le_bytes).collect())
+        .collect();
+    let data_start = names_start + names.iter().map(|name| name.len()).sum::<usize>();
+    let mut buffer = vec![0; names_start];
+    buffer[0..4].copy_from_slice(MAGIC);
+    buffer[0x0C..0x10].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    buffer[0x10..0x18].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    buffer[0x18..0x20].copy_from_slice(b"00000001");
+    buffer[0x20..0x28].copy_from_slice(&(ENTRY_HEADER_SIZE as u64).to_le_bytes());
+    buffer[0x28..0x30].copy_from_slice(&(data_start as u64).to_le_bytes());
+    buffer[0x30] = 1;
+    buffer[0x31] = 0x20;
+    for (index, name) in names.iter().enumerate() {
+        let entry_start = HEADER_SIZE + index * ENTRY_HEADER_SIZE;
+        buffer[entry_start..entry_start + 4].copy_from_slice(&0x50u32.to_le_bytes());
+        buffer[entry_start + 4..entry_start + 8].copy_from_slice(&[0xFF; 4]);
+        let name_offset = buffer.len() as u32;
+        buffer[entry_start + 20..entry_start + 24].copy_from_slice(&name_offset.to_le_bytes());
+        buffer.extend_from_slice(name);
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        let entry_start = HEADER_SIZE + index * ENTRY_HEADER_SIZE;
+        let data_offset = buffer.len() as u32;
+        buffer[entry_start + 8..entry_start + 16].copy_from_slice(&(entry.len() as u64).to_le_bytes());
+        buffer[entry_start + 16..entry_start + 20].copy_from_slice(&data_offset.to_le_bytes());
+        buffer.extend_from_slice(entry);
+    }
+    return buffer;
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+
+    #[test]
+    fn reads_entries() {
+        let buffer = build(&[vec![1, 2, 3], vec![], vec![4; 0x20]]);
+        let container = Bnd4::parse(&buffer).unwrap();
+        assert_eq!(container.header.entry_count, 3);
+        assert_eq!(container.header.version, "00000001");
+        assert_eq!(container.header.entry_header_size, 0x20);
+        assert_eq!(container.header.data_start, container.entries().next().unwrap().data_offset);
+        assert!(container.header.unicode);
+        assert_eq!(container.header.format, 0x20);
+
+        let entries: Vec<(usize, u32, &str, usize)> = container.entries()
+            .map(|entry| (entry.index, entry.flags, entry.name.as_str(), entry.data_length))
+            .collect();
+        assert_eq!(entries, vec![(0, 0x50, "USER_DATA000", 3), (1, 0x50, "USER_DATA001", 0), (2, 0x50, "USER_DATA002", 0x20)]);
+        // Each name is 12 characters and a null terminator.
+        assert_eq!(container.find("USER_DATA001").unwrap().name_offset, HEADER_SIZE + 3 * 0x20 + 26);
+    }
+
+    #[test]
+    fn finds_entries_by_name() {
+        let buffer = build(&[vec![1, 2, 3], vec![], vec![4; 0x20]]);
+        let container = Bnd4::parse(&buffer).unwrap();
+        assert_eq!(container.data(container.find(&user_data_name(0)).unwrap()), &[1, 2, 3]);
+        assert_eq!(container.data(container.find(&user_data_name(1)).unwrap()), &[] as &[u8]);
+        assert_eq!(container.data(container.find(&user_data_name(2)).unwrap()), &[4; 0x20][..]);
+        assert_eq!(container.find("USER_DATA003").err(), Some(SaveError::MissingEntry(String::from("USER_DATA003"))));
+    }
+
+    #[test]
+    fn reads_single_byte_names() {
+        let mut buffer = build(&[vec![7]]);
+        buffer[0x30] = 0;
+        let name_offset = HEADER_SIZE + 0x20;
+        buffer[name_offset..name_offset + 5].copy_from_slice(b"DATA\0");
+        let container = Bnd4::parse(&buffer).unwrap();
+        assert_eq!(container.data(container.find("DATA").unwrap()), &[7]);
+    }
+
+    #[test]
+    fn bad_name_offset_is_an_error() {
+        let mut buffer = build(&[vec![7]]);
+        let name_offset = (buffer.len() as u32).to_le_bytes();
+        buffer[HEADER_SIZE + 20..HEADER_SIZE + 24].copy_from_slice(&name_offset);
+        assert!(matches!(Bnd4::parse(&buffer).err(), Some(SaveError::Truncated { .. })));
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes_never_panic(buffer in prop::collection::vec(any::<u8>(), 0..0x200)) {
+            let _ = Bnd4::parse(&buffer);
+        }
+
+        // Random values in the header and entry table, where the bounds checks matter.
+        #[test]
+        fn any_header_never_panics(mut buffer in prop::collection::vec(any::<u8>(), HEADER_SIZE..0x200)) {
+            buffer[0..4].copy_from_slice(MAGIC);
+            let _ = Bnd4::parse(&buffer);
+        }
+
+        #[test]
+        fn truncated_container_never_panics(
+            entries in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..0x40), 0..8),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let buffer = build(&entries);
+            let cut = cut.index(buffer.len() + 1);
+            let truncated = &buffer[..cut];
+            match Bnd4::parse(truncated) {
+                // Only possible when every entry still fits, then the data has to be what went in.
+                Ok(container) => {
+                    for (index, entry) in entries.iter().enumerate() {
+                        prop_assert_eq!(container.data(container.find(&user_data_name(index)).unwrap()), &entry[..]);
+                    }
+                },
+                Err(_) => prop_assert!(cut < buffer.len()),
+            }
+        }
+    }
+}
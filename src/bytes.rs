@@ -0,0 +1,74 @@
+use crate::error::SaveError;
+
+// Every offset we read out of a save file goes through these so a truncated or half-written
+// file turns into a SaveError instead of panicking the watcher thread.
+
+pub fn check_range(buffer: &[u8], offset: usize, length: usize) -> Result<(), SaveError> {
+    match offset.checked_add(length) {
+        Some(end) if end <= buffer.len() => Ok(()),
+        _ => Err(SaveError::Truncated { offset, length }),
+    }
+}
+
+pub fn slice(buffer: &[u8], offset: usize, length: usize) -> Result<&[u8], SaveError> {
+    check_range(buffer, offset, length)?;
+    return Ok(&buffer[offset..offset + length]);
+}
+
+pub fn read_u32(buffer: &[u8], offset: usize) -> Result<u32, SaveError> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(slice(buffer, offset, 4)?);
+    return Ok(u32::from_le_bytes(bytes));
+}
+
+pub fn read_u64(buffer: &[u8], offset: usize) -> Result<u64, SaveError> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(slice(buffer, offset, 8)?);
+    return Ok(u64::from_le_bytes(bytes));
+}
+
+// Reads a null terminated UTF-16 string, stopping early after max_chars for fixed size fields.
+pub fn read_utf16(buffer: &[u8], offset: usize, max_chars: usize) -> Result<String, SaveError> {
+    let field = slice(buffer, offset, max_chars.saturating_mul(2))?;
+    let units: Vec<u16> = field.chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+    return Ok(String::from_utf16_lossy(&units));
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+
+    // Offsets near usize::MAX are where an unchecked offset + length would overflow.
+    fn offset() -> impl Strategy<Value = usize> {
+        return prop_oneof![0..0x100usize, any::<usize>(), (usize::MAX - 0x10)..=usize::MAX];
+    }
+
+    #[test]
+    fn reads_in_range() {
+        let buffer = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+        assert_eq!(read_u32(&buffer, 4), Ok(0x08070605));
+        assert_eq!(read_u64(&buffer, 0), Ok(0x0807060504030201));
+        assert_eq!(read_u32(&buffer, 5), Err(SaveError::Truncated { offset: 5, length: 4 }));
+        assert_eq!(read_utf16(&[b'h', 0, b'i', 0, 0, 0, b'x', 0], 0, 4), Ok(String::from("hi")));
+    }
+
+    proptest! {
+        #[test]
+        fn reads_never_panic(
+            buffer in prop::collection::vec(any::<u8>(), 0..0x40),
+            offset in offset(),
+            length in offset(),
+        ) {
+            let in_range = offset.checked_add(length).is_some_and(|end| end <= buffer.len());
+            prop_assert_eq!(check_range(&buffer, offset, length).is_ok(), in_range);
+            prop_assert_eq!(slice(&buffer, offset, length).is_ok(), in_range);
+            let _ = read_u32(&buffer, offset);
+            let _ = read_u64(&buffer, offset);
+            let _ = read_utf16(&buffer, offset, length);
+        }
+    }
+}
@@ -1,29 +1,186 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::error::Error;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
-use crate::games::*;
+use crate::atomicfile;
+use crate::atomicfile::SyncMode;
+use crate::deaths;
+use crate::games;
+use crate::games::SaveGame;
+use crate::profiles;
+use crate::profiles::Profile;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub enum Game {
-    Ds1,
-    Dsr,
-    Ds2,
-    Ds2Sotfs,
-    Ds3,
-    Sekiro,
+// Either a slot number, or "auto" to follow whichever character was played last.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum SaveSlot {
+    Slot(usize),
+    Mode(SlotMode),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotMode {
+    Auto,
+}
+
+impl Default for SaveSlot {
+    fn default() -> SaveSlot {
+        return SaveSlot::Slot(0);
+    }
+}
+
+impl std::fmt::Display for SaveSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveSlot::Slot(slot) => write!(f, "{}", slot),
+            SaveSlot::Mode(SlotMode::Auto) => write!(f, "auto"),
+        }
+    }
+}
+
+// How the save file is watched for changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    // The operating system tells us when the file changes, falls back to polling where that isn't possible.
+    Native,
+    // Check the file every poll_interval_ms, for network shares and mounts that don't send change events.
+    Poll,
+}
+
+impl Default for WatchMode {
+    fn default() -> WatchMode {
+        return WatchMode::Native;
+    }
+}
+
+// How an output file is written, see deaths::save.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    // Just the filled in template, replaced on every change.
+    Plain,
+    // The filled in template along with the numbers behind it, for browser sources and scripts.
+    Json,
+    // A line added to the end for every change, a log of the session.
+    CsvAppend,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        return OutputFormat::Plain;
+    }
+}
+
+// One of the [[outputs]] in the config, every one is updated on each death.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_template")]
+    pub template: String,
+    #[serde(default)]
+    pub format: OutputFormat,
+}
+
+fn default_template() -> String {
+    return String::from(deaths::DEFAULT_TEMPLATE);
+}
+
+fn default_poll_interval() -> u64 {
+    return 2000;
+}
+
+// Settings left out of a game's table fall back to these defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GameConfig {
+    pub override_file_location: String,
+    pub save_slot: SaveSlot,
+    // The user ID folder to use when there is more than one, blank picks the most recently saved.
+    pub profile: String,
+}
+
+impl GameConfig {
+    pub fn pinned_profile(&self) -> Option<&str> {
+        return Some(self.profile.trim()).filter(|profile| !profile.is_empty());
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigFile {
     pub output_deaths_location: std::path::PathBuf,
-    pub current_game: Game,
-    pub dsr_config: dsr::DsrConfig,
-    pub ds1_config: ds1::Ds1Config,
-    pub ds2_config: ds2::Ds2Config,
-    pub ds3_config: ds3::Ds3Config,
-    pub sekiro_config: sekiro::SekiroConfig,
+    // What gets written to output_deaths_location, see deaths::render for the {placeholders}.
+    #[serde(default = "default_template")]
+    pub output_template: String,
+    // The id of one of the games in games::GAMES, e.g. "Dsr", or "auto".
+    pub current_game: String,
+    // Extra Wine prefixes to look for saves in, Steam's Proton prefixes are found automatically.
+    #[serde(default)]
+    pub wine_prefixes: Vec<String>,
+    #[serde(default)]
+    pub watcher: WatchMode,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval_ms: u64,
+    // Whether to flush outputs to disk as they're written, see atomicfile::SyncMode.
+    #[serde(default)]
+    pub sync_writes: SyncMode,
+    // Keep running and wait for the save to be created instead of stopping when there isn't one yet.
+    #[serde(default)]
+    pub wait_for_save: bool,
+    // Extra files to write alongside output_deaths_location.
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+    // A JSON file with the game, character and counts for scripts, see statefile.rs. Empty for none.
+    #[serde(default)]
+    pub state_file: PathBuf,
+    // Keyed by game id, games missing from the file fall back to the defaults.
+    #[serde(default)]
+    pub games: BTreeMap<String, GameConfig>,
+}
+
+// current_game value that picks the game from the save file itself.
+pub const AUTO_GAME: &str = "auto";
+
+impl ConfigFile {
+    // "auto" is swapped for the detected game in get_save_location, so this is always a real game after that.
+    pub fn game(&self) -> &'static dyn SaveGame {
+        return games::find(&self.current_game).expect("current_game is checked in load_config");
+    }
+
+    pub fn game_config(&self) -> GameConfig {
+        return self.config_for(&self.current_game);
+    }
+
+    // output_deaths_location is kept as the first output so older configs carry on working,
+    // leaving it empty turns it off for anyone who only wants [[outputs]].
+    pub fn outputs(&self) -> Vec<OutputConfig> {
+        let mut outputs = Vec::new();
+        if self.output_deaths_location != Path::new("") {
+            outputs.push(OutputConfig {
+                path: self.output_deaths_location.clone(),
+                template: self.output_template.clone(),
+                format: OutputFormat::Plain,
+            });
+        }
+        outputs.extend(self.outputs.iter().cloned());
+        return outputs;
+    }
+
+    // --profile pins the user ID folder for the game being tracked. With "auto" that game isn't
+    // known until a save has been picked, so it's pinned for every game that could be picked.
+    pub fn pin_profile(&mut self, profile: &str) {
+        for game in candidate_games(self) {
+            self.games.entry(game.id().to_string()).or_default().profile = profile.to_string();
+        }
+    }
+
+    pub fn config_for(&self, game_id: &str) -> GameConfig {
+        return self.games.get(game_id).cloned().unwrap_or_default();
+    }
 }
 
 impl std::fmt::Display for ConfigFile {
@@ -35,12 +192,18 @@ impl std::fmt::Display for ConfigFile {
 pub fn new() -> ConfigFile {
     return ConfigFile {
         output_deaths_location: PathBuf::from("deaths.txt"),
-        current_game: Game::Dsr,
-        dsr_config: dsr::new(),
-        ds1_config: ds1::new(),
-        ds2_config: ds2::new(),
-        ds3_config: ds3::new(),
-        sekiro_config: sekiro::new(),
+        output_template: default_template(),
+        current_game: games::dsr::Dsr.id().to_string(),
+        wine_prefixes: Vec::new(),
+        watcher: WatchMode::Native,
+        poll_interval_ms: default_poll_interval(),
+        sync_writes: SyncMode::None,
+        wait_for_save: false,
+        outputs: Vec::new(),
+        state_file: PathBuf::new(),
+        games: games::GAMES.iter()
+            .map(|game| (game.id().to_string(), GameConfig::default()))
+            .collect(),
     }
 }
 
@@ -66,32 +229,334 @@ pub fn load_config() -> Result<ConfigFile, Box<dyn Error>> {
         },
         Ok(_n) => (),
     }
-    let config: ConfigFile = match toml::from_str(file_buffer.as_str()) {
-        Err(err) => {
-            return Err(Box::new(err));
-        },
-        Ok(f) => f,
-    };
+    let config = parse_config(&file_buffer)?;
+    if config.current_game != AUTO_GAME && games::find(&config.current_game).is_none() {
+        let mut ids: Vec<&str> = games::GAMES.iter().map(|game| game.id()).collect();
+        ids.push(AUTO_GAME);
+        return Err(format!("Unknown current_game \"{}\", expected one of: {}", config.current_game, ids.join(", ")).into());
+    }
+    return Ok(config);
+}
+
+fn parse_config(file_buffer: &str) -> Result<ConfigFile, Box<dyn Error>> {
+    let mut value: toml::Value = toml::from_str(file_buffer)?;
+    migrate_legacy_tables(&mut value)?;
+    let config: ConfigFile = value.try_into()?;
+    // A zero interval would have the poll watcher check the save as fast as it can.
+    if config.poll_interval_ms == 0 {
+        return Err("poll_interval_ms has to be more than 0".into());
+    }
     return Ok(config);
 }
 
+// The per-game tables from before they moved under [games], and the game each was for.
+// Back then "Ds2" meant Scholar of the First Sin, so that's what ds2_config was for.
+const LEGACY_TABLES: &[(&str, &str)] = &[
+    ("ds1_config", "Ds1"),
+    ("dsr_config", "Dsr"),
+    ("ds2_config", "Ds2Sotfs"),
+    ("ds3_config", "Ds3"),
+    ("sekiro_config", "Sekiro"),
+];
+
+// Older configs have a table per game at the top level, e.g. [ds3_config]. They're read as the
+// matching [games.<id>] table so nobody's save_slot or override_file_location quietly resets,
+// a [games.<id>] table wins if a config has both.
+fn migrate_legacy_tables(config: &mut toml::Value) -> Result<(), Box<dyn Error>> {
+    let table = match config.as_table_mut() {
+        None => return Ok(()),
+        Some(t) => t,
+    };
+    let mut migrated = false;
+    for (legacy_name, game_id) in LEGACY_TABLES {
+        let legacy_table = match table.remove(*legacy_name) {
+            None => continue,
+            Some(t) => t,
+        };
+        migrated = true;
+        let games = table.entry("games").or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+        let games = games.as_table_mut().ok_or("games in config.toml should be a table")?;
+        if games.contains_key(*game_id) {
+            println!("WARNING: Ignoring [{}] in config.toml, [games.{}] is used instead.", legacy_name, game_id);
+        } else {
+            println!("NOTE: Reading [{}] in config.toml as [games.{}], rename the table to silence this.", legacy_name, game_id);
+            games.insert(game_id.to_string(), legacy_table);
+        }
+    }
+    if migrated && table.get("current_game").and_then(|game| game.as_str()) == Some("Ds2") {
+        println!("NOTE: Reading current_game = \"Ds2\" as \"Ds2Sotfs\", which it meant in this config's version.");
+        table.insert(String::from("current_game"), toml::Value::String(String::from("Ds2Sotfs")));
+    }
+    return Ok(());
+}
+
 pub fn save_config(config: &ConfigFile) {
     match toml::to_string(config) {
         Err(err) => println!("{}", err),
         Ok(file_data) => {
-            let mut file = File::create("config.toml").unwrap();
-            file.write_all(file_data.as_bytes()).unwrap();
+            if let Err(e) = atomicfile::write(Path::new("config.toml"), file_data.as_bytes(), config.sync_writes) {
+                println!("Couldn't write config.toml: {}", e);
+            }
         },
     };
 }
 
-pub fn get_save_location(config: &ConfigFile) -> PathBuf {
-    return match config.current_game {
-        Game::Ds1 => ds1::get_save_location().unwrap(),
-        Game::Dsr => dsr::get_save_location().unwrap(),
-        Game::Ds2 => ds2::get_save_location().unwrap(),
-        Game::Ds2Sotfs => PathBuf::from(""),
-        Game::Ds3 => ds3::get_save_location().unwrap(),
-        Game::Sekiro => sekiro::get_save_location().unwrap(),
+pub fn get_save_location(config: &mut ConfigFile) -> Result<PathBuf, Box<dyn Error>> {
+    if config.current_game == AUTO_GAME {
+        return detect_save_location(config);
+    }
+    let game = config.game();
+    let profiles = find_profiles(config, game)?;
+    let save_location = profiles::choose(game, profiles, config.game_config().pinned_profile())?;
+    warn_if_other_game(game, &save_location);
+    return Ok(save_location);
+}
+
+// Both DS2 editions share a save folder, and "Ds2" used to mean Scholar of the First Sin,
+// so point it out when the file looks like it belongs to a different game.
+fn warn_if_other_game(game: &dyn SaveGame, save_location: &Path) {
+    let file_buffer = match std::fs::read(save_location) {
+        Err(_) => return,
+        Ok(b) => b,
     };
+    if game.detect(&file_buffer) {
+        return;
+    }
+    if let Some(other) = games::detect(&file_buffer) {
+        println!("WARNING: This save file looks like it belongs to {}.", other.name());
+        println!("Set current_game = \"{}\" (or \"auto\") in config.toml if the death count looks wrong.", other.id());
+    }
+}
+
+// Whether there's a save to track yet, checked quietly so it can be polled while waiting for one.
+pub fn has_save(config: &ConfigFile) -> bool {
+    return candidate_games(config).into_iter().any(|game| match find_profiles(config, game) {
+        Err(_) => false,
+        Ok(profiles) => profiles.iter().any(|profile| profile.modified.is_some()),
+    });
+}
+
+// The files next to the save that are tracked when they change. Games with more than one save in the
+// folder (e.g. Seamless Co-op's copy) follow whichever was saved last, unless override_file_location
+// points at one of them.
+pub fn save_file_names(config: &ConfigFile, save_location: &Path) -> Vec<OsString> {
+    let override_location = config.game_config().override_file_location;
+    let is_file = !override_location.trim().is_empty()
+        && expand_path(override_location.trim()).map(|path| path.is_file()).unwrap_or(false);
+    if is_file {
+        return save_location.file_name().map(|name| name.to_os_string()).into_iter().collect();
+    }
+    return config.game().file_names().iter().map(OsString::from).collect();
+}
+
+// Every folder a save for the current game could turn up in, whether or not it exists yet.
+pub fn wait_roots(config: &ConfigFile) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut roots = Vec::new();
+    for game in candidate_games(config) {
+        let override_location = config.config_for(game.id()).override_file_location;
+        if override_location.trim().is_empty() {
+            roots.extend(games::save_roots(game, &wine_prefixes(config)?));
+        } else {
+            roots.push(expand_path(override_location.trim())?);
+        }
+    }
+    return Ok(roots);
+}
+
+fn candidate_games(config: &ConfigFile) -> Vec<&'static dyn SaveGame> {
+    if config.current_game != AUTO_GAME {
+        return vec![config.game()];
+    }
+    return games::GAMES.to_vec();
+}
+
+// Takes the most recently saved file out of every game's save folders,
+// then checks which game it really belongs to.
+fn detect_save_location(config: &mut ConfigFile) -> Result<PathBuf, Box<dyn Error>> {
+    let mut newest: Option<Profile> = None;
+    for game in candidate_games(config) {
+        let profiles = match find_profiles(config, game) {
+            Err(e) => {
+                println!("WARNING: Skipping {}: {}", game.name(), e);
+                continue;
+            },
+            Ok(p) => p,
+        };
+        let game_config = config.config_for(game.id());
+        let pinned = game_config.pinned_profile();
+        for profile in profiles {
+            if pinned.is_some_and(|id| id != profile.id) || profile.modified.is_none() {
+                continue;
+            }
+            if newest.as_ref().is_none_or(|newest| profile.modified > newest.modified) {
+                newest = Some(profile);
+            }
+        }
+    }
+
+    let profile = newest.ok_or("No save file found for any supported game")?;
+    let file_buffer = std::fs::read(&profile.save_location)?;
+    let game = games::detect(&file_buffer).ok_or_else(|| {
+        format!("{} doesn't look like a save file for any supported game", profile.save_location.display())
+    })?;
+    println!("Detected Game: {}", game.name());
+    config.current_game = game.id().to_string();
+    return Ok(profile.save_location);
+}
+
+fn find_profiles(config: &ConfigFile, game: &dyn SaveGame) -> Result<Vec<Profile>, Box<dyn Error>> {
+    let override_location = config.config_for(game.id()).override_file_location;
+    if override_location.trim().is_empty() {
+        return Ok(games::save_roots(game, &wine_prefixes(config)?).iter()
+            .flat_map(|root| profiles::find(game, root))
+            .collect());
+    }
+
+    // The override can point straight at a save file, or at a folder to look for one in.
+    let path = expand_path(override_location.trim())?;
+    if path.is_file() {
+        let id = path.parent().and_then(|folder| folder.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        return Ok(vec![profiles::from_file(game, id, path)]);
+    }
+    if !path.is_dir() {
+        return Err(format!("override_file_location \"{}\" does not exist", path.display()).into());
+    }
+    let profiles = profiles::find(game, &path);
+    if profiles.is_empty() {
+        let names = game.file_names().join(" or ");
+        return Err(format!("override_file_location \"{}\" does not contain {}", path.display(), names).into());
+    }
+    return Ok(profiles);
+}
+
+fn wine_prefixes(config: &ConfigFile) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    return config.wine_prefixes.iter()
+        .map(|prefix| expand_path(prefix))
+        .collect();
+}
+
+// Expands a leading ~ to the home folder, and $VAR, ${VAR} and %VAR% to environment variables.
+fn expand_path(raw: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let mut expanded = String::new();
+    let mut rest = raw;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        let home = dirs::home_dir().ok_or("Unable to find the home folder to expand ~")?;
+        expanded.push_str(&home.to_string_lossy());
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find(['$', '%']) {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remaining) = if rest[start..].starts_with("${") {
+            match after[1..].find('}') {
+                Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                None => return Err(format!("Unclosed ${{ in \"{}\"", raw).into()),
+            }
+        } else if rest[start..].starts_with('%') {
+            match after.find('%') {
+                Some(end) => (&after[..end], &after[end + 1..]),
+                // A lone % is just part of the path.
+                None => ("", after),
+            }
+        } else {
+            let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+
+        if name.is_empty() {
+            expanded.push_str(&rest[start..start + 1]);
+        } else {
+            match std::env::var(name) {
+                Ok(value) => expanded.push_str(&value),
+                Err(_) => return Err(format!("Environment variable {} in \"{}\" is not set", name, raw).into()),
+            }
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+    return Ok(PathBuf::from(expanded));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the baseline version generated, with a couple of settings changed.
+    const LEGACY_CONFIG: &str = r#"
+output_deaths_location = "deaths.txt"
+current_game = "Ds3"
+
+[dsr_config]
+override_file_location = ""
+save_slot = 0
+
+[ds1_config]
+override_file_location = ""
+save_slot = 0
+
+[ds2_config]
+override_file_location = "/saves/ds2"
+save_slot = 1
+
+[ds3_config]
+override_file_location = "/tmp/oldcfg/nothere"
+save_slot = 2
+
+[sekiro_config]
+override_file_location = ""
+save_slot = 0
+"#;
+
+    #[test]
+    fn reads_legacy_tables() {
+        let config = parse_config(LEGACY_CONFIG).unwrap();
+        assert_eq!(config.current_game, "Ds3");
+        assert_eq!(config.game_config().override_file_location, "/tmp/oldcfg/nothere");
+        assert_eq!(config.game_config().save_slot, SaveSlot::Slot(2));
+        let ds2 = config.config_for("Ds2Sotfs");
+        assert_eq!(ds2.override_file_location, "/saves/ds2");
+        assert_eq!(ds2.save_slot, SaveSlot::Slot(1));
+        assert!(!config.games.contains_key("Ds2"));
+    }
+
+    #[test]
+    fn legacy_ds2_means_sotfs() {
+        let config = parse_config(&LEGACY_CONFIG.replace("current_game = \"Ds3\"", "current_game = \"Ds2\"")).unwrap();
+        assert_eq!(config.current_game, "Ds2Sotfs");
+        assert_eq!(config.game_config().override_file_location, "/saves/ds2");
+    }
+
+    #[test]
+    fn games_table_wins_over_legacy() {
+        let text = format!("{}\n[games.Ds3]\nsave_slot = 5\n", LEGACY_CONFIG);
+        let config = parse_config(&text).unwrap();
+        assert_eq!(config.game_config().save_slot, SaveSlot::Slot(5));
+        assert_eq!(config.game_config().override_file_location, "");
+    }
+
+    #[test]
+    fn zero_poll_interval_is_an_error() {
+        assert!(parse_config("output_deaths_location = \"deaths.txt\"\ncurrent_game = \"auto\"\npoll_interval_ms = 0\n").is_err());
+    }
+
+    #[test]
+    fn profile_is_pinned_for_every_game_in_auto() {
+        let mut config = parse_config("output_deaths_location = \"deaths.txt\"\ncurrent_game = \"auto\"\n").unwrap();
+        config.pin_profile("76561198000000000");
+        for game in candidate_games(&config) {
+            assert_eq!(config.config_for(game.id()).pinned_profile(), Some("76561198000000000"));
+        }
+        assert!(!config.games.contains_key(AUTO_GAME));
+    }
+
+    #[test]
+    fn current_layout_is_untouched() {
+        let text = "output_deaths_location = \"deaths.txt\"\ncurrent_game = \"Ds2\"\n[games.Ds2]\nsave_slot = 3\n";
+        let config = parse_config(text).unwrap();
+        assert_eq!(config.current_game, "Ds2");
+        assert_eq!(config.game_config().save_slot, SaveSlot::Slot(3));
+    }
 }
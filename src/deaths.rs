@@ -1,25 +1,344 @@
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::atomicfile;
+use crate::atomicfile::SyncMode;
+use crate::config::{OutputConfig, OutputFormat};
+use crate::games::SlotInfo;
+use crate::statefile::State;
 
-pub fn save(output_location: &PathBuf, new_death_count: u32, current_death_count: u32) {
-    if new_death_count == current_death_count {
-        return;
-    }
+// What output_template defaults to, just the number.
+pub const DEFAULT_TEMPLATE: &str = "{deaths}";
+
+const CSV_HEADER: &str = "timestamp,deaths,change,session_deaths,text";
 
-    let deaths_str = new_death_count.to_string();
-    let mut deaths_file = match File::create(output_location) {
-        Err(_) => {
-            println!("Unable to open deaths.txt");
-            return;
+// A json output is the state file's fields, see statefile.rs, and the filled in template as text.
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    text: &'a str,
+    #[serde(flatten)]
+    state: State<'a>,
+}
+
+pub fn save(output: &OutputConfig, change: &DeathChange, game_id: &str, game: &str, sync: SyncMode) {
+    let text = render(&output.template, change, game);
+    let result = match output.format {
+        OutputFormat::Plain => atomicfile::write(&output.path, text.as_bytes(), sync),
+        OutputFormat::Json => {
+            let json = JsonOutput {
+                text: &text,
+                state: State::new(change, game_id, game),
+            };
+            // Nothing in there can fail to serialize.
+            let contents = serde_json::to_string_pretty(&json).unwrap();
+            atomicfile::write(&output.path, contents.as_bytes(), sync)
         },
-        Ok(f) => f,
-    };
-    match deaths_file.write_all(&deaths_str.as_bytes()) {
-        Err(_) => {
-            println!("Couldn't write to deaths.txt");
-            return;
+        OutputFormat::CsvAppend => {
+            let line = format!(
+                "{},{},{},{},{}",
+                unix_seconds(change.timestamp), change.new, change.delta, change.session_deaths, csv_field(&text),
+            );
+            atomicfile::append(&output.path, CSV_HEADER, &line, sync)
         },
-        Ok(_) => (),
     };
+    if let Err(e) = result {
+        println!("Couldn't write to {}: {}", output.path.display(), e);
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    return time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+}
+
+// Quoted if it has anything in it that would break the columns up.
+fn csv_field(text: &str) -> String {
+    if text.contains(&[',', '"', '\n', '\r'][..]) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    return text.to_string();
+}
+
+// Fills in the {placeholders} in an output template. Anything the game's save doesn't give us,
+// like the character name in DS3, comes out as "-". Anything in braces that isn't a placeholder is
+// left as it is.
+pub fn render(template: &str, change: &DeathChange, game: &str) -> String {
+    // One pass over the template, so braces inside a value (a character called "{deaths}") aren't filled in again.
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| placeholder(&rest[1..end], change, game).map(|value| (value, end)));
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(&value);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            },
+        }
+    }
+    rendered.push_str(rest);
+    return rendered;
+}
+
+fn placeholder(name: &str, change: &DeathChange, game: &str) -> Option<String> {
+    let unknown = || String::from("-");
+    let info = &change.slot;
+    let value = match name {
+        "deaths" => change.new.to_string(),
+        "session_deaths" => change.session_deaths.to_string(),
+        "character" => info.name.clone().unwrap_or_else(unknown),
+        "game" => game.to_string(),
+        "slot" => info.slot.to_string(),
+        "play_time" => info.play_time.map(format_play_time).unwrap_or_else(unknown),
+        "deaths_per_hour" => info.play_time
+            .filter(|play_time| play_time.as_secs() > 0)
+            .map(|play_time| format!("{:.1}", change.new as f64 / (play_time.as_secs_f64() / 3600.0)))
+            .unwrap_or_else(unknown),
+        _ => return None,
+    };
+    return Some(value);
+}
+
+pub fn format_play_time(play_time: Duration) -> String {
+    let seconds = play_time.as_secs();
+    return format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+}
+
+// A change in the death count, handed to everything subscribed to the tracker.
+pub struct DeathChange {
+    // None for the first count read after starting.
+    pub old: Option<u32>,
+    pub new: u32,
+    // Can be negative, e.g. after switching to a character with fewer deaths.
+    pub delta: i64,
+    pub timestamp: SystemTime,
+    // The first count read after starting, and the deaths since then.
+    // Both start again from the new character's count when the tracked character changes.
+    pub session_start: u32,
+    pub session_deaths: u32,
+    // The slot the count was read from, for the name, play time etc.
+    pub slot: SlotInfo,
+    // The save it was read from, which can change when a folder holds more than one.
+    pub save_file: PathBuf,
+}
+
+type Listener = Box<dyn FnMut(&DeathChange) + Send>;
+
+// Remembers the last count so outputs and hooks only hear about real changes,
+// rather than every time the game saves.
+pub struct DeathTracker {
+    first: Option<u32>,
+    last: Option<u32>,
+    // The save file and slot the last count came from, a different one is a different character.
+    character: Option<(PathBuf, usize)>,
+    listeners: Vec<Listener>,
+}
+
+impl DeathTracker {
+    pub fn new() -> DeathTracker {
+        return DeathTracker {
+            first: None,
+            last: None,
+            character: None,
+            listeners: Vec::new(),
+        };
+    }
+
+    pub fn subscribe<F>(&mut self, listener: F)
+    where F: FnMut(&DeathChange) + Send + 'static {
+        self.listeners.push(Box::new(listener));
+    }
+
+    // Returns the change if the count or the character is different from the last one.
+    pub fn update(&mut self, info: SlotInfo, save_file: &Path) -> Option<DeathChange> {
+        let deaths = info.deaths;
+        let character = (save_file.to_path_buf(), info.slot);
+        let switched = self.character.as_ref().is_some_and(|last| *last != character);
+        if self.last == Some(deaths) && !switched {
+            return None;
+        }
+        // The old character's deaths say nothing about this one's, so the session starts over.
+        if switched {
+            self.first = None;
+        }
+        self.character = Some(character);
+        let first = *self.first.get_or_insert(deaths);
+        let change = DeathChange {
+            old: self.last,
+            new: deaths,
+            delta: deaths as i64 - self.last.unwrap_or(deaths) as i64,
+            timestamp: SystemTime::now(),
+            session_start: first,
+            session_deaths: deaths.saturating_sub(first),
+            slot: info,
+            save_file: save_file.to_path_buf(),
+        };
+        self.last = Some(deaths);
+        for listener in &mut self.listeners {
+            listener(&change);
+        }
+        return Some(change);
+    }
+
+    pub fn summary(&self) -> String {
+        return match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                format!("Deaths: {} ({} this session)", last, last.saturating_sub(first))
+            },
+            _ => String::from("No deaths counted this session."),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(slot: usize, deaths: u32) -> SlotInfo {
+        return SlotInfo { slot, name: None, level: None, play_time: None, deaths, fingerprint: 0 };
+    }
+
+    #[test]
+    fn only_changes_are_emitted() {
+        let save_file = Path::new("DS30000.sl2");
+        let mut tracker = DeathTracker::new();
+        assert_eq!(tracker.update(slot(0, 10), save_file).unwrap().session_deaths, 0);
+        assert!(tracker.update(slot(0, 10), save_file).is_none());
+        let change = tracker.update(slot(0, 12), save_file).unwrap();
+        assert_eq!((change.old, change.delta, change.session_deaths), (Some(10), 2, 2));
+    }
+
+    #[test]
+    fn switching_slots_starts_the_session_again() {
+        let save_file = Path::new("DS30000.sl2");
+        let mut tracker = DeathTracker::new();
+        tracker.update(slot(0, 10), save_file);
+        tracker.update(slot(0, 12), save_file);
+        // Same count, different character.
+        let change = tracker.update(slot(1, 12), save_file).unwrap();
+        assert_eq!((change.slot.slot, change.session_start, change.session_deaths), (1, 12, 0));
+        let change = tracker.update(slot(1, 15), save_file).unwrap();
+        assert_eq!((change.session_start, change.session_deaths), (12, 3));
+        // Back to a character with fewer deaths.
+        let change = tracker.update(slot(0, 4), save_file).unwrap();
+        assert_eq!((change.delta, change.session_start, change.session_deaths), (-11, 4, 0));
+        assert_eq!(tracker.summary(), "Deaths: 4 (0 this session)");
+    }
+
+    #[test]
+    fn switching_files_starts_the_session_again() {
+        let mut tracker = DeathTracker::new();
+        tracker.update(slot(0, 9), Path::new("userdata0000"));
+        let change = tracker.update(slot(0, 9), Path::new("userdata0001")).unwrap();
+        assert_eq!((change.session_start, change.session_deaths), (9, 0));
+    }
+
+    fn change(name: Option<&str>, play_time: Option<u64>) -> DeathChange {
+        return DeathChange {
+            old: Some(14),
+            new: 15,
+            delta: 1,
+            timestamp: UNIX_EPOCH,
+            session_start: 12,
+            session_deaths: 3,
+            slot: SlotInfo {
+                slot: 2,
+                name: name.map(String::from),
+                level: None,
+                play_time: play_time.map(Duration::from_secs),
+                deaths: 15,
+                fingerprint: 0,
+            },
+            save_file: PathBuf::from("ER0000.sl2"),
+        };
+    }
+
+    #[test]
+    fn renders_every_placeholder() {
+        let change = change(Some("Tarnished"), Some(7200));
+        let template = "{deaths} {session_deaths} {character} {game} {slot} {play_time} {deaths_per_hour}";
+        assert_eq!(render(template, &change, "ELDEN RING"), "15 3 Tarnished ELDEN RING 2 2:00:00 7.5");
+        assert_eq!(render(DEFAULT_TEMPLATE, &change, "ELDEN RING"), "15");
+    }
+
+    #[test]
+    fn unknown_values_render_as_a_dash() {
+        assert_eq!(render("{character} {play_time} {deaths_per_hour}", &change(None, None), "DARK SOULS III"), "- - -");
+        // No play time yet, rather than dividing by zero.
+        assert_eq!(render("{deaths_per_hour}", &change(None, Some(0)), "ELDEN RING"), "-");
+    }
+
+    #[test]
+    fn values_are_not_filled_in_again() {
+        let change = change(Some("{deaths}"), None);
+        assert_eq!(render("{character} {game}", &change, "{slot}"), "{deaths} {slot}");
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        let change = change(None, None);
+        assert_eq!(render("{unknown} {deaths", &change, "ELDEN RING"), "{unknown} {deaths");
+        assert_eq!(render("{{deaths}} }{", &change, "ELDEN RING"), "{15} }{");
+    }
+
+    #[test]
+    fn formats_play_time() {
+        assert_eq!(format_play_time(Duration::from_secs(0)), "0:00:00");
+        assert_eq!(format_play_time(Duration::from_secs(3661)), "1:01:01");
+        assert_eq!(format_play_time(Duration::from_secs(123 * 3600 + 59 * 60 + 9)), "123:59:09");
+    }
+
+    fn output(folder: &Path, name: &str, template: &str, format: OutputFormat) -> OutputConfig {
+        return OutputConfig { path: folder.join(name), template: template.to_string(), format };
+    }
+
+    #[test]
+    fn saves_plain() {
+        let folder = atomicfile::test_folder("saves-plain");
+        let output = output(&folder, "deaths.txt", "Deaths: {deaths}", OutputFormat::Plain);
+        save(&output, &change(None, None), "EldenRing", "ELDEN RING", SyncMode::None);
+        assert_eq!(std::fs::read_to_string(&output.path).unwrap(), "Deaths: 15");
+    }
+
+    #[test]
+    fn saves_json() {
+        let folder = atomicfile::test_folder("saves-json");
+        let output = output(&folder, "deaths.json", "{character}: {deaths}", OutputFormat::Json);
+        save(&output, &change(Some("Tarnished"), Some(7200)), "EldenRing", "ELDEN RING", SyncMode::None);
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&output.path).unwrap()).unwrap();
+        assert_eq!(json["text"], "Tarnished: 15");
+        assert_eq!(json["game_id"], "EldenRing");
+        assert_eq!(json["character"], "Tarnished");
+        assert_eq!(json["play_time_seconds"], 7200);
+        assert_eq!(json["deaths"], 15);
+        assert_eq!(json["change"], 1);
+        assert_eq!(json["session_deaths"], 3);
+        assert_eq!(json["last_change"], 0);
+    }
+
+    #[test]
+    fn csv_header_is_written_once() {
+        let folder = atomicfile::test_folder("csv-header");
+        let output = output(&folder, "deaths.csv", "{character}", OutputFormat::CsvAppend);
+        save(&output, &change(Some("Solaire"), None), "Dsr", "DARK SOULS: REMASTERED", SyncMode::None);
+        save(&output, &change(Some("Solaire"), None), "Dsr", "DARK SOULS: REMASTERED", SyncMode::None);
+        let expected = format!("{}\n0,15,1,3,Solaire\n0,15,1,3,Solaire\n", CSV_HEADER);
+        assert_eq!(std::fs::read_to_string(&output.path).unwrap(), expected);
+    }
+
+    #[test]
+    fn csv_text_is_quoted_when_it_needs_to_be() {
+        let folder = atomicfile::test_folder("csv-quoting");
+        let output = output(&folder, "deaths.csv", "{character}", OutputFormat::CsvAppend);
+        save(&output, &change(Some("Sol, \"the\"\nSun"), None), "Dsr", "DARK SOULS: REMASTERED", SyncMode::None);
+        let expected = format!("{}\n0,15,1,3,\"Sol, \"\"the\"\"\nSun\"\n", CSV_HEADER);
+        assert_eq!(std::fs::read_to_string(&output.path).unwrap(), expected);
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("line\r"), "\"line\r\"");
+        assert_eq!(csv_field("plain"), "plain");
+    }
 }
@@ -0,0 +1,54 @@
+// Everything that can go wrong while reading a death count out of a save file.
+// Callers should treat any of these as "no new count", never as zero deaths.
+#[derive(Debug, PartialEq)]
+pub enum SaveError {
+    WrongSize(usize),
+    BadMagic,
+    SlotOutOfRange { slot: usize, slot_count: usize },
+    // No character has been created in the slot, there's no count to read.
+    EmptySlot { slot: usize },
+    DecryptFailed,
+    Truncated { offset: usize, length: usize },
+    ChecksumMismatch { slot: usize },
+    UnexpectedData { offset: usize },
+    // The BND4 container doesn't have an entry by that name.
+    MissingEntry(String),
+}
+
+impl SaveError {
+    // Errors we'd expect from reading the file while the game was still writing it,
+    // worth reading the file again for rather than reporting straight away.
+    pub fn is_partial_write(&self) -> bool {
+        return matches!(self,
+            SaveError::WrongSize(_)
+            | SaveError::BadMagic
+            | SaveError::DecryptFailed
+            | SaveError::Truncated { .. }
+            | SaveError::ChecksumMismatch { .. }
+            | SaveError::UnexpectedData { .. });
+    }
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::WrongSize(size) => write!(f, "Save file size {} is invalid", size),
+            SaveError::BadMagic => write!(f, "Save file header is not recognised"),
+            SaveError::SlotOutOfRange { slot, slot_count } => {
+                write!(f, "Save slot {} not found, save file only has {} slots", slot, slot_count)
+            },
+            SaveError::EmptySlot { slot } => write!(f, "Save slot {} is empty, there's no character in it", slot),
+            SaveError::DecryptFailed => write!(f, "Unable to decrypt save file"),
+            SaveError::Truncated { offset, length } => {
+                write!(f, "Save file is truncated, needed {} bytes at 0x{:X}", length, offset)
+            },
+            SaveError::ChecksumMismatch { slot } => write!(f, "Checksum mismatch in save slot {}", slot),
+            SaveError::UnexpectedData { offset } => {
+                write!(f, "Save data at 0x{:X} isn't laid out the way we expect", offset)
+            },
+            SaveError::MissingEntry(name) => write!(f, "Save file has no {} entry", name),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
@@ -1,73 +1,77 @@
-use std::path::PathBuf;
-use std::error::Error;
-use serde::{Serialize, Deserialize};
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo};
 
 const FILE_SIZE_NEW:usize = 4326432;
 const FILE_SIZE_GFWL:usize = 4330480;
 const SLOT_SIZE:usize = 393616;
+const SLOT_COUNT:usize = 10;
 const FIRST_SLOT_OFFSET:usize = 704;
 const DEATHS_LOCATION:usize = 127272;
+const LEVEL_LOCATION:usize = 0x88;
+// 13 characters and the null terminator.
+const NAME_LOCATION:usize = 0x100;
+const NAME_LENGTH:usize = 14;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Ds1Config {
-    pub override_file_location: String,
-    pub save_slot: usize,
-}
+pub struct Ds1;
 
-impl std::fmt::Display for Ds1Config {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Ds1Config Error")
+impl SaveGame for Ds1 {
+    fn id(&self) -> &'static str {
+        return "Ds1";
     }
-}
 
-pub fn new() -> Ds1Config {
-    return Ds1Config {
-        override_file_location: String::from(""),
-        save_slot: 0,
+    fn name(&self) -> &'static str {
+        return "DARK SOULS: Prepare To Die Edition";
     }
-}
 
-pub fn parse(slot: usize, file_buffer: &Vec<u8>) -> u32 {
-    // Noticed that old save files from GWFL-era are different sized from post GWFL-era.
-    // Not sure what the difference is, both read the same for retrieving deaths.
-    if file_buffer.len() != FILE_SIZE_NEW && file_buffer.len() != FILE_SIZE_GFWL {
-        println!("ERROR: Save file size is invalid");
-        return 0;
+    // e.g. ~/Documents/NBGI/darksouls/[12345678]/DRAKS0005.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::Documents("NBGI/darksouls");
     }
-    let slot_start = FIRST_SLOT_OFFSET + (slot * SLOT_SIZE);
-    let slot_end = slot_start + SLOT_SIZE;
-    let slot_data = &file_buffer[slot_start..slot_end];
 
-    let mut deaths_arr = [0; 4];
-    deaths_arr.copy_from_slice(&slot_data[DEATHS_LOCATION..DEATHS_LOCATION+4]);
-    return u32::from_le_bytes(deaths_arr);
-}
+    fn steam_app_id(&self) -> u32 {
+        return 211420;
+    }
+
+    // There have been instances where the file name is all lowercase
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["DRAKS0005.sl2", "draks0005.sl2"];
+    }
+
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
 
-pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let mut save_location = dirs::document_dir().unwrap();
-    save_location.push(r"NBGI/darksouls");
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        // Noticed that old save files from GWFL-era are different sized from post GWFL-era.
+        // Not sure what the difference is, both read the same for retrieving deaths.
+        if file_buffer.len() != FILE_SIZE_NEW && file_buffer.len() != FILE_SIZE_GFWL {
+            return Err(SaveError::WrongSize(file_buffer.len()));
+        }
+        if slot >= SLOT_COUNT {
+            return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+        }
+        let slot_start = FIRST_SLOT_OFFSET + (slot * SLOT_SIZE);
+        let slot_data = bytes::slice(file_buffer, slot_start, SLOT_SIZE)?;
 
-    // The save files are within a child folder that is named with the users unique ID or username.
-    // e.g. ~/Documents/NBDI/Dark Souls/[12345678]/DRAKS0005.sl2
-    // Since I haven't figured out if there is a way to get this ID, we just take the first child folder.
-    // TODO: Let the user view and select a user folder if there is more than one.
-    return match std::fs::read_dir(&save_location) {
-        Err(err) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            Err(Box::new(err))
-        },
-        Ok(paths) => {
-            for path in paths {
-                // Let's hope there is only one directory in here.
-                save_location.push(path.unwrap().path());
-                // There have been instances where the file name is all lowercase
-                // TODO: Implement better checking for save file
-                save_location.push("DRAKS0005.sl2");
-                break;
-            }
-            Ok(save_location)
-        },
-    };
+        // Every character has a name, a blank one means the slot has never been used.
+        let name = bytes::read_utf16(slot_data, NAME_LOCATION, NAME_LENGTH)?;
+        if name.is_empty() {
+            return Err(SaveError::EmptySlot { slot });
+        }
+        return Ok(SlotInfo {
+            slot,
+            name: Some(name),
+            level: Some(bytes::read_u32(slot_data, LEVEL_LOCATION)?),
+            // Not read for this game, see the slots table in the README.
+            play_time: None,
+            deaths: bytes::read_u32(slot_data, DEATHS_LOCATION)?,
+            fingerprint: games::fingerprint(slot_data),
+        });
+    }
+
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return file_buffer.len() == FILE_SIZE_NEW || file_buffer.len() == FILE_SIZE_GFWL;
+    }
 }
@@ -1,120 +1,163 @@
-use std::path::PathBuf;
-use std::error::Error;
 use aes::Aes128;
 use block_modes::{BlockMode, Cbc};
-use block_modes::block_padding::{ZeroPadding};
-use serde::{Serialize, Deserialize};
+use block_modes::block_padding::NoPadding;
+use crate::bnd4;
+use crate::bnd4::Bnd4;
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo};
+
+// The slots are padded with zeros opposed to pkcs7 found in other DS games.
+// Decrypting without unpadding keeps trailing zeros that are really part of the slot data.
+type Aes128CbcNoPad = Cbc<Aes128, NoPadding>;
+
+// Both editions share the save layout, only the key and file name are different.
+const KEY_ORIGINAL:[u8; 16] = [0xB7, 0xFD, 0x46, 0x3E, 0x4A, 0x9C, 0x11, 0x02, 0xDF, 0x17, 0x39, 0xE5, 0xF3, 0xB2, 0xA5, 0x0F];
+const KEY_SOTFS:[u8; 16] = [0x59, 0x9F, 0x9B, 0x69, 0x96, 0x40, 0xA5, 0x52, 0x36, 0xEE, 0x2D, 0x70, 0x83, 0x5E, 0xC7, 0x44];
 
-// ZeroPadding (Pad with Zeros) opposed to pkcs7 found in other DS games.
-type Aes128CbcZero = Cbc<Aes128, ZeroPadding>;
+const AES_BLOCKLEN:usize = 16;
+const SLOT_COUNT:usize = 10;
 
-// DS2 Original
-//const KEY:[u8; 16] = [0xB7, 0xFD, 0x46, 0x3E, 0x4A, 0x9C, 0x11, 0x02, 0xDF, 0x17, 0x39, 0xE5, 0xF3, 0xB2, 0xA5, 0x0F];
-// DS2 SOTFS
-const KEY:[u8; 16] = [0x59, 0x9F, 0x9B, 0x69, 0x96, 0x40, 0xA5, 0x52, 0x36, 0xEE, 0x2D, 0x70, 0x83, 0x5E, 0xC7, 0x44];
+pub struct Ds2;
 
-const AES_BLOCKLEN:usize = 16;
+impl SaveGame for Ds2 {
+    fn id(&self) -> &'static str {
+        return "Ds2";
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Ds2Config {
-    pub override_file_location: String,
-    pub save_slot: usize,
-}
+    fn name(&self) -> &'static str {
+        return "DARK SOULS II";
+    }
+
+    // e.g. %APPDATA%/DarkSoulsII/[12345678]/DARKSII0000.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::AppData("DarkSoulsII");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 236430;
+    }
+
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["DARKSII0000.sl2"];
+    }
 
-impl std::fmt::Display for Ds2Config {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Ds2Config Error")
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
+
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        return read_slot(&KEY_ORIGINAL, slot, file_buffer);
+    }
+
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return detect(&KEY_ORIGINAL, file_buffer);
     }
 }
 
-pub fn new() -> Ds2Config {
-    return Ds2Config {
-        override_file_location: String::from(""),
-        save_slot: 0,
+pub struct Ds2Sotfs;
+
+impl SaveGame for Ds2Sotfs {
+    fn id(&self) -> &'static str {
+        return "Ds2Sotfs";
+    }
+
+    fn name(&self) -> &'static str {
+        return "DARK SOULS II: Scholar of the First Sin";
+    }
+
+    // e.g. %APPDATA%/DarkSoulsII/[12345678]/DS2SOFS0000.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::AppData("DarkSoulsII");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 335300;
+    }
+
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["DS2SOFS0000.sl2"];
+    }
+
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
+
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        return read_slot(&KEY_SOTFS, slot, file_buffer);
+    }
+
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return detect(&KEY_SOTFS, file_buffer);
     }
 }
 
-pub fn parse(slot: usize, file_buffer: &Vec<u8>) -> u32 {
-    // The first "file" in BND4 starts at 0x40, but in this case it seems to be a metadata file.
-    // So we're skipping the first file and going to 0x60 which is the first character save slot.
-    let meta_start = 0x60 + slot*0x20;
-
-    // When reading the 32 bytes (0x20), the data layout is as follows
-    // ID (4 bytes)
-    // Flag (4 bytes)
-    // Data Length (4 Bytes)
-    // --unused-- (4 Bytes)
-    // Data Offset (4 Bytes)
-    // Name Offset (4 Bytes)
-    // --unused-- (4 Bytes)
-    // --unused-- (4 Bytes)
-    let data_length_vec = &file_buffer[meta_start+8..meta_start+12];
-    let mut data_length = [0; 4];
-    data_length.copy_from_slice(&data_length_vec[0..4]);
-    let data_length = u32::from_le_bytes(data_length);
-    let slot_offset_vec = &file_buffer[meta_start+16..meta_start+20];
-    let mut slot_offset = [0; 4];
-    slot_offset.copy_from_slice(&slot_offset_vec[0..4]);
-    let slot_offset = u32::from_le_bytes(slot_offset);
-    let slot_start = slot_offset as usize + AES_BLOCKLEN;
-    let slot_end = slot_start + data_length as usize - AES_BLOCKLEN;
-
-    let slot_data = &file_buffer[slot_start..slot_end];
-    let iv: Vec<u8> = file_buffer[slot_start-16..slot_start].to_vec();
-
-    let cipher = Aes128CbcZero::new_from_slices(&KEY, &iv).unwrap();
-    // TODO: Instead of println, return a custom error.
-    let decrypted_slot_data = match cipher.decrypt_vec(&slot_data) {
-        Err(e) => {
-            println!("ERROR: Unable to decrypt save file {:?}", e);
-            return 0;
-        },
-        Ok(b) => b,
-    };
-
-    //save_decrypted_file(slot, &decrypted_slot_data);
-
-    let mut deaths_arr = [0; 4];
+fn read_slot(key: &[u8; 16], slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+    let decrypted_slot_data = decrypt_slot(key, slot, file_buffer)?;
     let death_pointer = 0xCC;
-    //println!("Offset: {}", (current_pointer - starting_pointer));
-    deaths_arr.copy_from_slice(&decrypted_slot_data[death_pointer..death_pointer+4]);
-    return u32::from_le_bytes(deaths_arr);
+    // Only the death counter is read from DS2 slots, not the name, level or play time (see the
+    // slots table in the README), so a slot only counts as empty if the game has never written to it.
+    return Ok(SlotInfo {
+        slot,
+        name: None,
+        level: None,
+        play_time: None,
+        deaths: bytes::read_u32(&decrypted_slot_data, death_pointer)?,
+        fingerprint: games::fingerprint(&decrypted_slot_data),
+    });
+}
+
+// Without padding to check any key "decrypts", so this relies on the result looking like save data.
+fn detect(key: &[u8; 16], file_buffer: &[u8]) -> bool {
+    return games::first_used_slot(SLOT_COUNT, |slot| decrypt_slot(key, slot, file_buffer))
+        .is_some_and(|slot_data| games::looks_decrypted(&slot_data));
 }
 
-// TEMP
-/*fn save_decrypted_file(slot: usize, decrypted_file_buffer: &Vec<u8>) {
-    let file_name = format!("SAVESLOT{:03}", slot);
-    let mut file = File::create(file_name).unwrap();
-    file.write_all(decrypted_file_buffer).unwrap();
-}*/
-
-/*pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let save_location = PathBuf::from("DS2SOFS0000.sl2");
-    return Ok(save_location);
-}*/
-
-pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let mut save_location = dirs::config_dir().unwrap();
-    save_location.push(r"DarkSoulsII");
-
-    // The save files are within a child folder that is named with the users unique ID or username.
-    // Since I haven't figured out if there is a way to get this ID, we just take the first child folder.
-    // TODO: Let the user view and select a user folder if there is more than one.
-    return match std::fs::read_dir(&save_location) {
-        Err(err) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            Err(Box::new(err))
-        },
-        Ok(paths) => {
-            for path in paths {
-                // Let's hope there is only one directory in here.
-                save_location.push(path.unwrap().path());
-                save_location.push("DS2SOFS0000.sl2");
-                break;
-            }
-            Ok(save_location)
-        },
-    };
+fn decrypt_slot(key: &[u8; 16], slot: usize, file_buffer: &[u8]) -> Result<Vec<u8>, SaveError> {
+    if slot >= SLOT_COUNT {
+        return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+    }
+    let container = Bnd4::parse(file_buffer)?;
+    // USER_DATA000 seems to be a metadata file, the character slots start at USER_DATA001.
+    let entry_data = container.data(container.find(&bnd4::user_data_name(slot + 1))?);
+    if games::is_blank(entry_data) {
+        return Err(SaveError::EmptySlot { slot });
+    }
+    let iv = bytes::slice(entry_data, 0, AES_BLOCKLEN)?;
+    let slot_data = &entry_data[AES_BLOCKLEN..];
+
+    let cipher = Aes128CbcNoPad::new_from_slices(key, iv).unwrap();
+    return cipher.decrypt_vec(slot_data).map_err(|_| SaveError::DecryptFailed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bnd4;
+
+    fn slot(key: &[u8; 16], deaths: u32) -> Vec<u8> {
+        let mut data = vec![0; 0x400];
+        data[0xCC..0xD0].copy_from_slice(&deaths.to_le_bytes());
+        let iv = [0x3C; AES_BLOCKLEN];
+        let cipher = Aes128CbcNoPad::new_from_slices(key, &iv).unwrap();
+        let mut slot = iv.to_vec();
+        slot.extend(cipher.encrypt_vec(&data));
+        return slot;
+    }
+
+    // The metadata entry, then the character slots.
+    fn save(key: &[u8; 16], slots: &[Option<u32>]) -> Vec<u8> {
+        let mut entries = vec![vec![0; 0x60]];
+        entries.extend(slots.iter().map(|deaths| deaths.map_or_else(|| vec![0; 0x410], |deaths| slot(key, deaths))));
+        return bnd4::build(&entries);
+    }
+
+    #[test]
+    fn empty_slots_are_left_out() {
+        let file_buffer = save(&KEY_SOTFS, &[None, Some(44), None]);
+        games::tests::assert_empty_slots_left_out(&Ds2Sotfs, &file_buffer, &[0, 2], &[1]);
+        assert_eq!(Ds2Sotfs.read_slot(1, &file_buffer).unwrap().deaths, 44);
+        assert!(!Ds2.detect(&file_buffer));
+    }
 }
@@ -1,95 +1,133 @@
-use std::path::PathBuf;
-use std::error::Error;
 use aes::Aes128;
 use block_modes::{BlockMode, Cbc};
 use block_modes::block_padding::Pkcs7;
-use serde::{Serialize, Deserialize};
+use crate::bnd4;
+use crate::bnd4::Bnd4;
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo};
 
 type Aes128Cbc = Cbc<Aes128, Pkcs7>;
 
 const KEY:[u8; 16] = [0xFD, 0x46, 0x4D, 0x69, 0x5E, 0x69, 0xA3, 0x9A, 0x10, 0xE3, 0x19, 0xA7, 0xAC, 0xE8, 0xB7, 0xFA];
 const AES_BLOCKLEN:usize = 16;
+const SLOT_COUNT:usize = 10;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Ds3Config {
-    pub override_file_location: String,
-    pub save_slot: usize,
-}
+pub struct Ds3;
 
-impl std::fmt::Display for Ds3Config {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Ds3Config Error")
+impl SaveGame for Ds3 {
+    fn id(&self) -> &'static str {
+        return "Ds3";
     }
-}
 
-pub fn new() -> Ds3Config {
-    return Ds3Config {
-        override_file_location: String::from(""),
-        save_slot: 0,
+    fn name(&self) -> &'static str {
+        return "DARK SOULS III";
+    }
+
+    // e.g. %APPDATA%/DarkSoulsIII/[12345678]/DS30000.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::AppData("DarkSoulsIII");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 374320;
+    }
+
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["DS30000.sl2"];
+    }
+
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
+
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        let decrypted_slot_data = decrypt_slot(slot, file_buffer)?;
+        let data_offset = bytes::read_u32(&decrypted_slot_data, 0x24)? as usize;
+
+        let deaths_offset:usize = 0x6B;
+        let death_pointer = data_offset.checked_add(deaths_offset).ok_or(SaveError::Truncated {
+            offset: data_offset,
+            length: deaths_offset + 4,
+        })?;
+        // Only the death counter is read from DS3 slots, not the name, level or play time (see the
+        // slots table in the README), so a slot only counts as empty if the game has never written to it.
+        return Ok(SlotInfo {
+            slot,
+            name: None,
+            level: None,
+            play_time: None,
+            deaths: bytes::read_u32(&decrypted_slot_data, death_pointer)?,
+            fingerprint: games::fingerprint(&decrypted_slot_data),
+        });
+    }
+
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return games::first_used_slot(SLOT_COUNT, |slot| decrypt_slot(slot, file_buffer))
+            .is_some_and(|slot_data| games::looks_decrypted(&slot_data));
     }
 }
 
-pub fn parse(slot: usize, file_buffer: &Vec<u8>) -> u32 {
-    let meta_start = 0x40 + slot*0x20;
-    let data_length_vec = &file_buffer[meta_start+8..meta_start+12];
-    let mut data_length = [0; 4];
-    data_length.copy_from_slice(&data_length_vec[0..4]);
-    let data_length = u32::from_le_bytes(data_length);
-    let slot_offset_vec = &file_buffer[meta_start+16..meta_start+20];
-    let mut slot_offset = [0; 4];
-    slot_offset.copy_from_slice(&slot_offset_vec[0..4]);
-    let slot_offset = u32::from_le_bytes(slot_offset);
-    let slot_start = slot_offset as usize + AES_BLOCKLEN;
-    let slot_end = slot_start + data_length as usize - AES_BLOCKLEN;
-    let mut slot_data = &file_buffer[slot_start..slot_end];
-    let iv: Vec<u8> = file_buffer[slot_start-16..slot_start].to_vec();
-
-    //println!("Start: {}, Size: {}", slot_start, data_length);
-
-    let cipher = Aes128Cbc::new_from_slices(&KEY, &iv).unwrap();
-    // TODO: Instead of println, return a custom error.
-    let decrypted_slot_data = match cipher.decrypt_vec(&mut slot_data) {
-        Err(e) => {
-            println!("ERROR: Unable to decrypt save file {}", e);
-            return 0;
-        },
-        Ok(b) => b,
-    };
-
-    //save_decrypted_file(slot, &decrypted_slot_data);
-
-    let mut data_pointer = [0; 4];
-    data_pointer.copy_from_slice(&decrypted_slot_data[0x24..0x28]);
-    let data_offset = u32::from_le_bytes(data_pointer);
-
-    let mut deaths_arr = [0;4];
-    let deaths_offset:usize = 0x6B;
-    deaths_arr.copy_from_slice(&decrypted_slot_data[data_offset as usize+deaths_offset..data_offset as usize+deaths_offset + 4]);
-    return u32::from_le_bytes(deaths_arr);
+fn decrypt_slot(slot: usize, file_buffer: &[u8]) -> Result<Vec<u8>, SaveError> {
+    if slot >= SLOT_COUNT {
+        return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+    }
+    let container = Bnd4::parse(file_buffer)?;
+    let entry_data = container.data(container.find(&bnd4::user_data_name(slot))?);
+    if games::is_blank(entry_data) {
+        return Err(SaveError::EmptySlot { slot });
+    }
+    games::verify_checksum(slot, entry_data)?;
+    // The checksum gets decrypted as the first block, so everything is shifted by a block.
+    let iv = bytes::slice(entry_data, 0, AES_BLOCKLEN)?;
+    let slot_data = &entry_data[AES_BLOCKLEN..];
+
+    let cipher = Aes128Cbc::new_from_slices(&KEY, iv).unwrap();
+    return cipher.decrypt_vec(slot_data).map_err(|_| SaveError::DecryptFailed);
 }
 
-pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let mut save_location = dirs::config_dir().unwrap();
-    save_location.push(r"DarkSoulsIII");
-
-    // The save files are within a child folder that is named with the users unique ID or username.
-    // Since I haven't figured out if there is a way to get this ID, we just take the first child folder.
-    // TODO: Let the user view and select a user folder if there is more than one.
-    return match std::fs::read_dir(&save_location) {
-        Err(err) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            Err(Box::new(err))
-        },
-        Ok(paths) => {
-            for path in paths {
-                // Let's hope there is only one directory in here.
-                save_location.push(path.unwrap().path());
-                save_location.push("DS30000.sl2");
-                break;
-            }
-            Ok(save_location)
-        },
-    };
+#[cfg(test)]
+mod tests {
+    use block_modes::BlockMode;
+    use md5::{Digest, Md5};
+    use super::*;
+    use crate::bnd4;
+
+    // A slot laid out like the game writes it: checksum, IV, then the encrypted data.
+    fn slot(deaths: u32) -> Vec<u8> {
+        // Offsets in the decrypted data are a block further on, see decrypt_slot.
+        let mut data = vec![0; 0x400];
+        let data_offset = 0x100;
+        data[0x24 - AES_BLOCKLEN..0x28 - AES_BLOCKLEN].copy_from_slice(&(data_offset as u32).to_le_bytes());
+        let death_pointer = data_offset + 0x6B - AES_BLOCKLEN;
+        data[death_pointer..death_pointer + 4].copy_from_slice(&deaths.to_le_bytes());
+        let iv = [0x5A; AES_BLOCKLEN];
+        let cipher = Aes128Cbc::new_from_slices(&KEY, &iv).unwrap();
+        let mut entry = iv.to_vec();
+        entry.extend(cipher.encrypt_vec(&data));
+        let mut slot = Md5::digest(&entry).to_vec();
+        slot.extend(entry);
+        return slot;
+    }
+
+    fn save(slots: &[Option<u32>]) -> Vec<u8> {
+        let entries: Vec<Vec<u8>> = slots.iter()
+            .map(|deaths| deaths.map_or_else(|| vec![0; slot(0).len()], slot))
+            .collect();
+        return bnd4::build(&entries);
+    }
+
+    #[test]
+    fn reads_deaths() {
+        let file_buffer = save(&[Some(33), Some(7)]);
+        assert_eq!(Ds3.read_slot(0, &file_buffer).unwrap().deaths, 33);
+        assert_eq!(Ds3.read_slot(1, &file_buffer).unwrap().deaths, 7);
+    }
+
+    #[test]
+    fn empty_slots_are_left_out() {
+        let file_buffer = save(&[None, Some(12), None]);
+        games::tests::assert_empty_slots_left_out(&Ds3, &file_buffer, &[0, 2], &[1]);
+    }
 }
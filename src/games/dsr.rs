@@ -1,106 +1,170 @@
-use std::path::PathBuf;
-use std::error::Error;
 use aes::Aes128;
 use block_modes::{BlockMode, Cbc};
 use block_modes::block_padding::Pkcs7;
-use serde::{Serialize, Deserialize};
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo};
 
 type Aes128Cbc = Cbc<Aes128, Pkcs7>;
 
 const KEY:[u8; 16] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32, 0x10];
 const FILE_SIZE:usize = 4326608;
 const SLOT_SIZE:usize = 393264;
+const SLOT_COUNT:usize = 10;
 const FIRST_SLOT_OFFSET:usize = 704;
 const AES_BLOCKLEN:usize = 16;
+// Same places as DS1, shifted by a block since the IV is decrypted along with the slot.
+const LEVEL_LOCATION:usize = 0x88 + AES_BLOCKLEN;
+const NAME_LOCATION:usize = 0x100 + AES_BLOCKLEN;
+const NAME_LENGTH:usize = 14;
+// The character name is stored again at 0x1E4F0, this time only as long as it needs to be.
+// Everything after it, including the death counter, moves along by the length of the name.
+const NAME_BLOCK_LOCATION:usize = 0x1E4F0;
+const DEATHS_LOCATION:usize = 0x1F1C0;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DsrConfig {
-    pub override_file_location: String,
-    pub save_slot: usize,
-}
+pub struct Dsr;
 
-impl std::fmt::Display for DsrConfig {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DsrConfig Error")
+impl SaveGame for Dsr {
+    fn id(&self) -> &'static str {
+        return "Dsr";
     }
-}
 
-pub fn new() -> DsrConfig {
-    return DsrConfig {
-        override_file_location: String::from(""),
-        save_slot: 0,
+    fn name(&self) -> &'static str {
+        return "DARK SOULS: REMASTERED";
+    }
+
+    // e.g. ~/Documents/NBGI/DARK SOULS REMASTERED/[12345678]/DRAKS0005.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::Documents("NBGI/DARK SOULS REMASTERED");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 570940;
+    }
+
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["DRAKS0005.sl2"];
     }
-}
 
-pub fn parse(slot: usize, file_buffer: &Vec<u8>) -> u32 {
-    if file_buffer.len() != FILE_SIZE {
-        println!("ERROR: Save file size is invalid");
-        return 0;
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
     }
-    let slot_start = FIRST_SLOT_OFFSET + AES_BLOCKLEN + (slot * SLOT_SIZE);
-    let slot_end = slot_start + SLOT_SIZE - AES_BLOCKLEN;
-    let mut slot_data = &file_buffer[slot_start..slot_end];
-    let iv: Vec<u8> = slot_data[0..16].to_vec();
-
-    let cipher = Aes128Cbc::new_from_slices(&KEY, &iv).unwrap();
-    // TODO: Instead of println, return a custom error.
-    let decrypted_slot_data = match cipher.decrypt_vec(&mut slot_data) {
-        Err(_) => {
-            println!("ERROR: Unable to decrypt save file");
-            return 0;
-        },
-        Ok(b) => b,
-    };
-
-    // Figured out that the offset from 0x1E4F0 to 4 0x00's in a row,
-    // is the same offset from 0x1F1C0.
-    let mut running: bool = true;
-    let starting_pointer: usize = 124144;
-    let mut current_pointer: usize = 124144;
-    while running {
-        if decrypted_slot_data[current_pointer] == 0u8
-        && decrypted_slot_data[current_pointer+1] == 0u8
-        && decrypted_slot_data[current_pointer+2] == 0u8
-        && decrypted_slot_data[current_pointer+3] == 0u8 {
-            if current_pointer - starting_pointer != 0 {
-                current_pointer += 1;
-            }
-            running = false;
-        } else {
-            current_pointer += 1;
+
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        if file_buffer.len() != FILE_SIZE {
+            return Err(SaveError::WrongSize(file_buffer.len()));
+        }
+        if slot >= SLOT_COUNT {
+            return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+        }
+        // Each slot starts with an MD5 checksum, followed by the IV and the encrypted data.
+        let slot_start = FIRST_SLOT_OFFSET + (slot * SLOT_SIZE);
+        games::verify_checksum(slot, bytes::slice(file_buffer, slot_start, SLOT_SIZE)?)?;
+        let slot_data = bytes::slice(file_buffer, slot_start + games::CHECKSUM_LENGTH, SLOT_SIZE - games::CHECKSUM_LENGTH)?;
+        let iv = bytes::slice(slot_data, 0, AES_BLOCKLEN)?;
+
+        let cipher = Aes128Cbc::new_from_slices(&KEY, iv).unwrap();
+        let decrypted_slot_data = cipher.decrypt_vec(slot_data).map_err(|_| SaveError::DecryptFailed)?;
+
+        // Every character has a name, a blank one means the slot has never been used.
+        let name = bytes::read_utf16(&decrypted_slot_data, NAME_LOCATION, NAME_LENGTH)?;
+        if name.is_empty() {
+            return Err(SaveError::EmptySlot { slot });
         }
+        let death_pointer = DEATHS_LOCATION + name_block_length(&decrypted_slot_data)?;
+        return Ok(SlotInfo {
+            slot,
+            name: Some(name),
+            level: Some(bytes::read_u32(&decrypted_slot_data, LEVEL_LOCATION)?),
+            // Not read for this game, see the slots table in the README.
+            play_time: None,
+            deaths: bytes::read_u32(&decrypted_slot_data, death_pointer)?,
+            fingerprint: games::fingerprint(&decrypted_slot_data),
+        });
+    }
+
+    // The size alone gives it away, decrypting the first slot makes sure the key matches too.
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return file_buffer.len() == FILE_SIZE
+            && matches!(self.read_slot(0, file_buffer), Ok(_) | Err(SaveError::EmptySlot { .. }));
     }
+}
 
-    let mut deaths_arr = [0; 4];
-    let death_pointer = 127424 + (current_pointer - starting_pointer);
-    //println!("Offset: {}", (current_pointer - starting_pointer));
-    deaths_arr.copy_from_slice(&decrypted_slot_data[death_pointer..death_pointer+4]);
-    return u32::from_le_bytes(deaths_arr);
+// Length in bytes of the UTF-16 name at NAME_BLOCK_LOCATION, not counting the null terminator.
+fn name_block_length(decrypted_slot_data: &[u8]) -> Result<usize, SaveError> {
+    let name_block = bytes::slice(decrypted_slot_data, NAME_BLOCK_LOCATION, NAME_LENGTH * 2)?;
+    return name_block.chunks_exact(2)
+        .position(|pair| pair == [0, 0])
+        .map(|chars| chars * 2)
+        .ok_or(SaveError::UnexpectedData { offset: NAME_BLOCK_LOCATION });
 }
 
-pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let mut save_location = dirs::document_dir().unwrap();
-    save_location.push(r"NBGI/DARK SOULS REMASTERED");
-
-    // The save files are within a child folder that is named with the users unique ID or username.
-    // e.g. ~/Documents/NBDI/DARK SOULS REMASTERED/[12345678]/DRAKS0005.sl2
-    // Since I haven't figured out if there is a way to get this ID, we just take the first child folder.
-    // TODO: Let the user view and select a user folder if there is more than one.
-    return match std::fs::read_dir(&save_location) {
-        Err(err) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            Err(Box::new(err))
-        },
-        Ok(paths) => {
-            for path in paths {
-                // Let's hope there is only one directory in here.
-                save_location.push(path.unwrap().path());
-                save_location.push("DRAKS0005.sl2");
-                break;
-            }
-            Ok(save_location)
-        },
-    };
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+    use super::*;
+
+    const NAMES: [&str; 4] = ["Solaire", "A", "Thirteen Char", "日本語"];
+
+    fn utf16(name: &str) -> Vec<u8> {
+        return name.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect();
+    }
+
+    // The decrypted data starts with a block of junk where the IV was, see read_slot.
+    fn decrypted_slot(name: &str, deaths: u32, level: u32) -> Vec<u8> {
+        let name = utf16(name);
+        let mut data = vec![0; SLOT_SIZE - games::CHECKSUM_LENGTH - AES_BLOCKLEN * 2];
+        data[LEVEL_LOCATION - AES_BLOCKLEN..][..4].copy_from_slice(&level.to_le_bytes());
+        data[NAME_LOCATION - AES_BLOCKLEN..][..name.len()].copy_from_slice(&name);
+        data[NAME_BLOCK_LOCATION - AES_BLOCKLEN..][..name.len()].copy_from_slice(&name);
+        data[DEATHS_LOCATION - AES_BLOCKLEN + name.len()..][..4].copy_from_slice(&deaths.to_le_bytes());
+        return data;
+    }
+
+    // A save with just the one slot filled in: checksum, IV, then the encrypted data.
+    fn save(slot: usize, name: &str, deaths: u32, level: u32) -> Vec<u8> {
+        let iv = [0x42; AES_BLOCKLEN];
+        let cipher = Aes128Cbc::new_from_slices(&KEY, &iv).unwrap();
+        let mut entry = iv.to_vec();
+        entry.extend(cipher.encrypt_vec(&decrypted_slot(name, deaths, level)));
+        let mut file_buffer = vec![0; FILE_SIZE];
+        let slot_start = FIRST_SLOT_OFFSET + (slot * SLOT_SIZE);
+        file_buffer[slot_start..][..games::CHECKSUM_LENGTH].copy_from_slice(&Md5::digest(&entry));
+        file_buffer[slot_start + games::CHECKSUM_LENGTH..][..entry.len()].copy_from_slice(&entry);
+        return file_buffer;
+    }
+
+    #[test]
+    fn name_block_length_is_the_name_in_bytes() {
+        for name in NAMES.iter() {
+            let mut data = vec![0; AES_BLOCKLEN];
+            data.extend(decrypted_slot(name, 0, 0));
+            assert_eq!(name_block_length(&data).unwrap(), name.encode_utf16().count() * 2, "{}", name);
+        }
+    }
+
+    #[test]
+    fn name_block_without_a_terminator_is_an_error() {
+        let mut data = vec![0; AES_BLOCKLEN];
+        data.extend(decrypted_slot("Fourteen Chars", 0, 0));
+        assert_eq!(name_block_length(&data).err(), Some(SaveError::UnexpectedData { offset: NAME_BLOCK_LOCATION }));
+    }
+
+    #[test]
+    fn reads_slot() {
+        for (i, name) in NAMES.iter().enumerate() {
+            let file_buffer = save(i, name, 100 + i as u32, 10 + i as u32);
+            let info = Dsr.read_slot(i, &file_buffer).unwrap();
+            assert_eq!(info.name.as_deref(), Some(*name));
+            assert_eq!(info.deaths, 100 + i as u32, "{}", name);
+            assert_eq!(info.level, Some(10 + i as u32));
+        }
+    }
+
+    #[test]
+    fn blank_name_is_an_empty_slot() {
+        let file_buffer = save(2, "", 0, 0);
+        assert_eq!(Dsr.read_slot(2, &file_buffer).err(), Some(SaveError::EmptySlot { slot: 2 }));
+    }
 }
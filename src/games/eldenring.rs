@@ -0,0 +1,335 @@
+use std::time::Duration;
+use crate::bnd4;
+use crate::bnd4::Bnd4;
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo, CHECKSUM_LENGTH};
+
+// The PC saves aren't encrypted. The BND4 holds the ten character slots in USER_DATA000 - 009,
+// a summary of every slot (names, levels, play time) in USER_DATA010, then a copy of the game's regulation.
+// Each entry starts with an MD5 checksum of the rest of the entry, see games::verify_checksum.
+//
+// These offsets come from the community documentation of the save layout and haven't been
+// checked against many real saves, so every step is sanity checked and a layout we don't
+// expect turns into an error instead of a bogus death count.
+const SLOT_COUNT:usize = 10;
+const SUMMARY_ENTRY:&str = "USER_DATA010";
+const SLOT_SIZE:usize = CHECKSUM_LENGTH + 0x280000;
+
+// Within the summary entry.
+const ACTIVE_SLOTS_LOCATION:usize = 0x1964;
+const SLOT_SUMMARY_LOCATION:usize = 0x196E;
+const SLOT_SUMMARY_SIZE:usize = 0x24C;
+// 16 characters and the null terminator.
+const NAME_LENGTH:usize = 17;
+const LEVEL_OFFSET:usize = 0x22;
+const PLAY_TIME_OFFSET:usize = 0x26;
+
+// Within a character slot, after the checksum, version, map ID and some unknown bytes.
+const ITEM_MAP_LOCATION:usize = CHECKSUM_LENGTH + 0x20;
+// Older saves have a couple fewer items in the map.
+const ITEM_MAP_LENGTH:usize = 0x1400;
+const ITEM_MAP_LENGTH_OLD:usize = 0x13FE;
+const ITEM_MAP_OLD_VERSION:u32 = 81;
+// Player stats, active effects, equipment, inventory, appearance, storage box and gestures
+// all sit between the item map and the list of unlocked map regions.
+const PLAYER_DATA_SIZE:usize = 0x1B0;
+const PLAYER_NAME_OFFSET:usize = 0x94;
+const PLAYER_DATA_TO_PROJECTILES:usize = 0xD0 + 0x58 * 3 + 0x9010 + 0x74 + 0x8C + 0x18;
+const PROJECTILES_TO_REGIONS:usize = 0x8C + 0x8 + 0x12F + 0x6010 + 0x100;
+// No list in a slot gets anywhere near this long, a bigger count means we're reading the wrong bytes.
+const LIST_LIMIT:usize = 1000;
+// Horse, menu and tutorial state, then the item pickup log and three flags before the counter.
+const REGIONS_TO_MENU:usize = 0x28 + 0x1 + 0x40;
+const MENU_TO_DEATHS:usize = 0x34 + 0x1B588 + 0x408 + 0x3;
+const MENU_LIMIT:usize = 0x10000;
+
+pub struct EldenRing;
+
+impl SaveGame for EldenRing {
+    fn id(&self) -> &'static str {
+        return "EldenRing";
+    }
+
+    fn name(&self) -> &'static str {
+        return "ELDEN RING";
+    }
+
+    // e.g. %APPDATA%/EldenRing/[76561198000000000]/ER0000.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::AppData("EldenRing");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 1245620;
+    }
+
+    // Seamless Co-op keeps its own copy of the save next to the normal one.
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["ER0000.sl2", "ER0000.co2"];
+    }
+
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
+
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        if slot >= SLOT_COUNT {
+            return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+        }
+        let container = Bnd4::parse(file_buffer)?;
+        let slot_data = slot_data(&container, slot)?;
+        let summary = container.data(container.find(SUMMARY_ENTRY)?);
+        // Reported as the slot after the last character slot, which is where it sits.
+        games::verify_checksum(SLOT_COUNT, summary)?;
+
+        // The game only clears the active flag when a character is deleted, the old data stays behind.
+        let active = bytes::slice(summary, ACTIVE_SLOTS_LOCATION + slot, 1)?[0] != 0;
+        if !active {
+            return Err(SaveError::EmptySlot { slot });
+        }
+
+        games::verify_checksum(slot, slot_data)?;
+        let slot_summary = SLOT_SUMMARY_LOCATION + slot * SLOT_SUMMARY_SIZE;
+        let name = bytes::read_utf16(summary, slot_summary, NAME_LENGTH)?;
+        let seconds_played = bytes::read_u32(summary, slot_summary + PLAY_TIME_OFFSET)?;
+        return Ok(SlotInfo {
+            slot,
+            level: Some(bytes::read_u32(summary, slot_summary + LEVEL_OFFSET)?),
+            play_time: Some(Duration::from_secs(seconds_played as u64)),
+            deaths: bytes::read_u32(slot_data, find_deaths(slot_data, &name)?)?,
+            name: Some(name),
+            fingerprint: games::fingerprint(slot_data),
+        });
+    }
+
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        return match Bnd4::parse(file_buffer) {
+            Err(_) => false,
+            Ok(container) => container.find(SUMMARY_ENTRY).is_ok()
+                && slot_data(&container, 0).is_ok(),
+        };
+    }
+}
+
+fn slot_data<'a>(container: &Bnd4<'a>, slot: usize) -> Result<&'a [u8], SaveError> {
+    let slot_data = container.data(container.find(&bnd4::user_data_name(slot))?);
+    if slot_data.len() != SLOT_SIZE {
+        return Err(SaveError::WrongSize(slot_data.len()));
+    }
+    return Ok(slot_data);
+}
+
+// The death counter sits after a few lists that change length as you play,
+// so we have to walk the slot to find it.
+fn find_deaths(slot_data: &[u8], name: &str) -> Result<usize, SaveError> {
+    let version = bytes::read_u32(slot_data, CHECKSUM_LENGTH)?;
+    let item_map_length = if version <= ITEM_MAP_OLD_VERSION { ITEM_MAP_LENGTH_OLD } else { ITEM_MAP_LENGTH };
+
+    // Each item is a handle and an item ID, weapons and armour carry some extra data after that.
+    let mut offset = ITEM_MAP_LOCATION;
+    for _ in 0..item_map_length {
+        let handle = bytes::read_u32(slot_data, offset)?;
+        offset += 8;
+        if handle != 0 {
+            offset += match handle & 0xF0000000 {
+                0x80000000 => 13,
+                0x90000000 => 8,
+                _ => 0,
+            };
+        }
+    }
+
+    // If the walk went wrong the name won't be where we expect, better to stop here than read garbage.
+    let player_name = bytes::read_utf16(slot_data, offset + PLAYER_NAME_OFFSET, NAME_LENGTH)?;
+    if player_name != name {
+        return Err(SaveError::UnexpectedData { offset });
+    }
+    offset += PLAYER_DATA_SIZE + PLAYER_DATA_TO_PROJECTILES;
+
+    let projectile_count = bytes::read_u32(slot_data, offset)? as usize;
+    if projectile_count > LIST_LIMIT {
+        return Err(SaveError::UnexpectedData { offset });
+    }
+    offset += 4 + projectile_count * 8;
+    offset += PROJECTILES_TO_REGIONS;
+
+    let region_count = bytes::read_u32(slot_data, offset)? as usize;
+    if region_count > LIST_LIMIT {
+        return Err(SaveError::UnexpectedData { offset });
+    }
+    offset += 4 + region_count * 4;
+    offset += REGIONS_TO_MENU;
+
+    let menu_length = bytes::read_u32(slot_data, offset + 4)? as usize;
+    if menu_length > MENU_LIMIT {
+        return Err(SaveError::UnexpectedData { offset });
+    }
+    offset += 8 + menu_length;
+    return Ok(offset + MENU_TO_DEATHS);
+}
+
+// These saves are put together from the community documentation of the layout, written out with
+// plain numbers rather than the constants above so a typo in one of those gets caught. They
+// check the walk through the slot, not that the documentation matches the game.
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+    use super::*;
+    use crate::bnd4;
+
+    struct Character {
+        name: &'static str,
+        level: u32,
+        seconds_played: u32,
+        deaths: u32,
+        version: u32,
+        weapons: usize,
+        armour: usize,
+        projectiles: usize,
+        regions: usize,
+        menu_length: usize,
+    }
+
+    const TARNISHED: Character = Character {
+        name: "Tarnished",
+        level: 80,
+        seconds_played: 12 * 3600 + 65,
+        deaths: 12,
+        version: 0xC8,
+        weapons: 3,
+        armour: 2,
+        projectiles: 2,
+        regions: 5,
+        menu_length: 0x1000,
+    };
+
+    fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+        buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_name(buffer: &mut [u8], offset: usize, name: &str) {
+        for (i, unit) in name.encode_utf16().enumerate() {
+            buffer[offset + i * 2..offset + i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    fn fix_checksum(entry: &mut [u8]) {
+        let checksum = Md5::digest(&entry[0x10..]);
+        entry[..0x10].copy_from_slice(&checksum[..]);
+    }
+
+    fn slot(character: &Character) -> Vec<u8> {
+        let mut slot = vec![0; 0x280010];
+        put_u32(&mut slot, 0x10, character.version);
+        let item_count = if character.version <= 81 { 0x13FE } else { 0x1400 };
+        let mut offset = 0x30;
+        for i in 0..item_count {
+            if i < character.weapons {
+                put_u32(&mut slot, offset, 0x80000000 | i as u32);
+                offset += 8 + 13;
+            } else if i < character.weapons + character.armour {
+                put_u32(&mut slot, offset, 0x90000000 | i as u32);
+                offset += 8 + 8;
+            } else {
+                offset += 8;
+            }
+        }
+        put_name(&mut slot, offset + 0x94, character.name);
+        offset += 0x1B0 + 0xD0 + 0x58 * 3 + 0x9010 + 0x74 + 0x8C + 0x18;
+        put_u32(&mut slot, offset, character.projectiles as u32);
+        offset += 4 + character.projectiles * 8;
+        offset += 0x8C + 0x8 + 0x12F + 0x6010 + 0x100;
+        put_u32(&mut slot, offset, character.regions as u32);
+        offset += 4 + character.regions * 4;
+        offset += 0x28 + 0x1 + 0x40;
+        put_u32(&mut slot, offset + 4, character.menu_length as u32);
+        offset += 8 + character.menu_length;
+        offset += 0x34 + 0x1B588 + 0x408 + 0x3;
+        put_u32(&mut slot, offset, character.deaths);
+        fix_checksum(&mut slot);
+        return slot;
+    }
+
+    fn save(characters: &[Option<Character>]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        let mut summary = vec![0; 0x60010];
+        for index in 0..10 {
+            match characters.get(index).and_then(|character| character.as_ref()) {
+                None => entries.push(vec![0; 0x280010]),
+                Some(character) => {
+                    entries.push(slot(character));
+                    summary[0x1964 + index] = 1;
+                    let slot_summary = 0x196E + index * 0x24C;
+                    put_name(&mut summary, slot_summary, character.name);
+                    put_u32(&mut summary, slot_summary + 0x22, character.level);
+                    put_u32(&mut summary, slot_summary + 0x26, character.seconds_played);
+                },
+            }
+        }
+        fix_checksum(&mut summary);
+        entries.push(summary);
+        // The regulation.
+        entries.push(vec![0; 0x100]);
+        return bnd4::build(&entries);
+    }
+
+    #[test]
+    fn reads_character() {
+        let file_buffer = save(&[Some(TARNISHED)]);
+        let info = EldenRing.read_slot(0, &file_buffer).unwrap();
+        assert_eq!(info.name.as_deref(), Some("Tarnished"));
+        assert_eq!(info.level, Some(80));
+        assert_eq!(info.play_time, Some(Duration::from_secs(12 * 3600 + 65)));
+        assert_eq!(info.deaths, 12);
+        assert!(EldenRing.detect(&file_buffer));
+    }
+
+    // The lists before the counter change length as you play, the walk has to follow them.
+    #[test]
+    fn follows_list_lengths() {
+        let melina = Character {
+            name: "Melina",
+            deaths: 7,
+            weapons: 10,
+            armour: 0,
+            projectiles: 0,
+            regions: 30,
+            menu_length: 0x20,
+            ..TARNISHED
+        };
+        let old_version = Character { name: "Old", deaths: 3, version: 81, ..TARNISHED };
+        let file_buffer = save(&[Some(TARNISHED), Some(melina), Some(old_version)]);
+        let deaths: Vec<u32> = EldenRing.list_slots(&file_buffer).unwrap().iter().map(|info| info.deaths).collect();
+        assert_eq!(deaths, vec![12, 7, 3]);
+    }
+
+    #[test]
+    fn empty_slots_are_left_out() {
+        let file_buffer = save(&[None, Some(TARNISHED)]);
+        games::tests::assert_empty_slots_left_out(&EldenRing, &file_buffer, &[0], &[1]);
+    }
+
+    // A name that doesn't match the summary means the walk went wrong, better an error than a bogus count.
+    #[test]
+    fn lost_walk_is_an_error() {
+        let mut file_buffer = save(&[Some(TARNISHED)]);
+        let container = Bnd4::parse(&file_buffer).unwrap();
+        let slot_start = container.find("USER_DATA000").unwrap().data_offset;
+        // Turn the first piece of armour into a weapon, which carries more data than the walk expects.
+        put_u32(&mut file_buffer, slot_start + 0x30 + 3 * 21, 0x80000003);
+        let entry_end = slot_start + 0x280010;
+        fix_checksum(&mut file_buffer[slot_start..entry_end]);
+        assert!(matches!(EldenRing.read_slot(0, &file_buffer), Err(SaveError::UnexpectedData { .. })));
+    }
+
+    #[test]
+    fn bad_checksum_is_an_error() {
+        let mut file_buffer = save(&[Some(TARNISHED)]);
+        let container = Bnd4::parse(&file_buffer).unwrap();
+        let slot_start = container.find("USER_DATA000").unwrap().data_offset;
+        file_buffer[slot_start + 0x100] ^= 1;
+        assert_eq!(EldenRing.read_slot(0, &file_buffer).err(), Some(SaveError::ChecksumMismatch { slot: 0 }));
+    }
+}
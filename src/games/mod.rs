@@ -1,5 +1,271 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use md5::{Digest, Md5};
+use crate::bytes;
+use crate::error::SaveError;
+use crate::steam;
+
 pub mod ds1;
 pub mod dsr;
 pub mod ds2;
 pub mod ds3;
 pub mod sekiro;
+pub mod eldenring;
+
+// Every supported game, in the order they're listed to the user.
+// Adding a game means adding its module above and an entry here, nothing else.
+pub static GAMES: &[&dyn SaveGame] = &[
+    &ds1::Ds1,
+    &dsr::Dsr,
+    &ds2::Ds2,
+    &ds2::Ds2Sotfs,
+    &ds3::Ds3,
+    &sekiro::Sekiro,
+    &eldenring::EldenRing,
+];
+
+// Where a game keeps its saves, relative to a Windows user folder.
+// Kept relative so the same folder can be found natively or inside a Proton / Wine prefix.
+pub enum SaveFolder {
+    Documents(&'static str),
+    AppData(&'static str),
+}
+
+impl SaveFolder {
+    fn native(&self) -> Option<PathBuf> {
+        return match self {
+            SaveFolder::Documents(folder) => dirs::document_dir().map(|dir| dir.join(folder)),
+            SaveFolder::AppData(folder) => dirs::config_dir().map(|dir| dir.join(folder)),
+        };
+    }
+
+    // Newer prefixes use the Vista style names, older ones the XP style names.
+    fn in_prefix_user(&self, user_folder: &Path) -> Vec<PathBuf> {
+        return match self {
+            SaveFolder::Documents(folder) => vec![
+                user_folder.join("Documents").join(folder),
+                user_folder.join("My Documents").join(folder),
+            ],
+            SaveFolder::AppData(folder) => vec![
+                user_folder.join("AppData/Roaming").join(folder),
+                user_folder.join("Application Data").join(folder),
+            ],
+        };
+    }
+}
+
+#[derive(Clone)]
+pub struct SlotInfo {
+    pub slot: usize,
+    // These are None for games they aren't read for, see the slots table in the README.
+    pub name: Option<String>,
+    pub level: Option<u32>,
+    pub play_time: Option<Duration>,
+    pub deaths: u32,
+    // Changes whenever anything in the slot's data changes, used to spot which slot the game last saved.
+    pub fingerprint: u64,
+}
+
+pub trait SaveGame: Sync {
+    // Used as the current_game value and the per-game table name in config.toml.
+    fn id(&self) -> &'static str;
+    fn name(&self) -> &'static str;
+    // Folder containing the per-user folders the save file lives in.
+    fn save_folder(&self) -> SaveFolder;
+    // Used to find the game's Proton prefix.
+    fn steam_app_id(&self) -> u32;
+    // The first name is the one the game normally writes, the rest are variations seen in the wild.
+    fn file_names(&self) -> &'static [&'static str];
+    fn slot_count(&self) -> usize;
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError>;
+    // Whether the file is a save for this game, used when current_game is "auto".
+    fn detect(&self, file_buffer: &[u8]) -> bool;
+
+    // Slots that can be read, empty slots and slots that fail to parse are left out.
+    // Only errors that mean the whole file is unreadable are returned.
+    fn list_slots(&self, file_buffer: &[u8]) -> Result<Vec<SlotInfo>, SaveError> {
+        let mut slots = Vec::new();
+        for slot in 0..self.slot_count() {
+            match self.read_slot(slot, file_buffer) {
+                Ok(info) => slots.push(info),
+                Err(SaveError::EmptySlot { .. }) => (),
+                Err(SaveError::WrongSize(size)) => return Err(SaveError::WrongSize(size)),
+                Err(SaveError::BadMagic) => return Err(SaveError::BadMagic),
+                // A bad checksum means the game was still writing the file, none of it can be trusted.
+                Err(SaveError::ChecksumMismatch { slot }) => return Err(SaveError::ChecksumMismatch { slot }),
+                Err(_) => (),
+            }
+        }
+        return Ok(slots);
+    }
+}
+
+pub const CHECKSUM_LENGTH:usize = 0x10;
+
+// Most of the games start each slot with an MD5 of the rest of the slot.
+// When it doesn't match we've read the file while the game was part way through writing it.
+pub fn verify_checksum(slot: usize, slot_data: &[u8]) -> Result<(), SaveError> {
+    let checksum = bytes::slice(slot_data, 0, CHECKSUM_LENGTH)?;
+    if &Md5::digest(&slot_data[CHECKSUM_LENGTH..])[..] != checksum {
+        return Err(SaveError::ChecksumMismatch { slot });
+    }
+    return Ok(());
+}
+
+// Slots the game has never written a character to are left as zeros.
+pub fn is_blank(slot_data: &[u8]) -> bool {
+    return slot_data.iter().all(|byte| *byte == 0);
+}
+
+// What read returns for the first slot with a character in it, or None if that fails or they're
+// all empty. Used by detect, since an empty slot looks the same in every game.
+pub fn first_used_slot<T, F>(slot_count: usize, read: F) -> Option<T>
+where F: Fn(usize) -> Result<T, SaveError> {
+    for slot in 0..slot_count {
+        match read(slot) {
+            Err(SaveError::EmptySlot { .. }) => continue,
+            result => return result.ok(),
+        }
+    }
+    return None;
+}
+
+pub fn fingerprint(slot_data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    slot_data.hash(&mut hasher);
+    return hasher.finish();
+}
+
+// Save data is mostly zeros, data decrypted with the wrong key is noise with hardly any.
+// Used to tell which game's key a save file was encrypted with.
+pub fn looks_decrypted(slot_data: &[u8]) -> bool {
+    let zeros = slot_data.iter().filter(|byte| **byte == 0).count();
+    return !slot_data.is_empty() && zeros * 8 > slot_data.len();
+}
+
+// Identifies the game a save file belongs to from its size, container and encryption.
+pub fn detect(file_buffer: &[u8]) -> Option<&'static dyn SaveGame> {
+    return GAMES.iter().find(|game| game.detect(file_buffer)).copied();
+}
+
+pub fn find(id: &str) -> Option<&'static dyn SaveGame> {
+    return GAMES.iter().find(|game| game.id() == id).copied();
+}
+
+// Every folder the game's saves could be in: the native one, then the game's Proton prefixes,
+// then any other Wine prefixes we've been told about.
+pub fn save_roots(game: &dyn SaveGame, wine_prefixes: &[PathBuf]) -> Vec<PathBuf> {
+    let folder = game.save_folder();
+    let mut prefixes = steam::proton_prefixes(game.steam_app_id());
+    prefixes.extend(wine_prefixes.iter().cloned());
+    if let Some(prefix) = std::env::var_os("WINEPREFIX") {
+        prefixes.push(PathBuf::from(prefix));
+    }
+
+    let mut roots: Vec<PathBuf> = folder.native().into_iter().collect();
+    for prefix in prefixes {
+        for user_folder in steam::prefix_users(&prefix) {
+            for root in folder.in_prefix_user(&user_folder) {
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+        }
+    }
+    return roots;
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+    use crate::bnd4;
+
+    // The whole file sizes DS1 (both versions) and DSR check for.
+    const FILE_SIZES: &[usize] = &[4326432, 4330480, 4326608];
+    // Entry sizes the BND4 games check for: DS2/DS3 sized blocks, Sekiro's and ELDEN RING's slots,
+    // and ELDEN RING's summary entry.
+    const ENTRY_SIZES: &[usize] = &[0, 0x10, 0x60, 0x2000, 0x3000, CHECKSUM_LENGTH + 0x100000, CHECKSUM_LENGTH + 0x280000];
+
+    // For a save built with characters in some slots and not others: the empty ones are an
+    // EmptySlot error, only the used ones are listed, and the game still recognises the file.
+    pub fn assert_empty_slots_left_out(game: &dyn SaveGame, file_buffer: &[u8], empty: &[usize], used: &[usize]) {
+        for slot in empty {
+            assert_eq!(game.read_slot(*slot, file_buffer).err(), Some(SaveError::EmptySlot { slot: *slot }));
+        }
+        let slots: Vec<usize> = game.list_slots(file_buffer).unwrap().iter().map(|info| info.slot).collect();
+        assert_eq!(slots, used);
+        assert!(game.detect(file_buffer));
+    }
+
+    // Everything the watcher can do with a save file, what comes back doesn't matter as long as nothing panics.
+    fn read_everything(file_buffer: &[u8]) {
+        for game in GAMES {
+            let _ = game.detect(file_buffer);
+            let _ = game.list_slots(file_buffer);
+            let _ = game.read_slot(game.slot_count(), file_buffer);
+            let _ = game.read_slot(usize::MAX, file_buffer);
+        }
+    }
+
+    // Random bytes scattered over a zeroed buffer, most of a real save is zeros too.
+    fn patch(buffer: &mut [u8], patches: &[(prop::sample::Index, u8)]) {
+        if buffer.is_empty() {
+            return;
+        }
+        for (index, value) in patches {
+            buffer[index.index(buffer.len())] = *value;
+        }
+    }
+
+    fn patches() -> impl Strategy<Value = Vec<(prop::sample::Index, u8)>> {
+        return prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..64);
+    }
+
+    // A container with the right shape for the BND4 games and random contents, with the
+    // checksums fixed up so reading gets past them.
+    fn container() -> impl Strategy<Value = Vec<u8>> {
+        let entry = (prop::sample::select(ENTRY_SIZES), patches(), any::<bool>());
+        return prop::collection::vec(entry, 0..12).prop_map(|entries| {
+            let entries: Vec<Vec<u8>> = entries.into_iter().map(|(size, patches, fix_checksum)| {
+                let mut entry = vec![0; size];
+                patch(&mut entry, &patches);
+                if fix_checksum && size >= CHECKSUM_LENGTH {
+                    let checksum = Md5::digest(&entry[CHECKSUM_LENGTH..]);
+                    entry[..CHECKSUM_LENGTH].copy_from_slice(&checksum[..]);
+                }
+                entry
+            }).collect();
+            return bnd4::build(&entries);
+        });
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn any_bytes_never_panic(buffer in prop::collection::vec(any::<u8>(), 0..0x1000)) {
+            read_everything(&buffer);
+        }
+
+        #[test]
+        fn right_sized_file_never_panics(size in prop::sample::select(FILE_SIZES), patches in patches()) {
+            let mut buffer = vec![0; size];
+            patch(&mut buffer, &patches);
+            read_everything(&buffer);
+        }
+    }
+
+    proptest! {
+        // The slots are megabytes each, so fewer of these.
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn any_container_never_panics(buffer in container(), cut in any::<prop::sample::Index>()) {
+            read_everything(&buffer);
+            read_everything(&buffer[..cut.index(buffer.len() + 1)]);
+        }
+    }
+}
@@ -1,84 +1,100 @@
-use std::path::PathBuf;
-use std::error::Error;
-use serde::{Serialize, Deserialize};
+use crate::bnd4;
+use crate::bnd4::Bnd4;
+use crate::bytes;
+use crate::error::SaveError;
+use crate::games;
+use crate::games::{SaveFolder, SaveGame, SlotInfo};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SekiroConfig {
-    pub override_file_location: String,
-    pub save_slot: usize,
-}
+const SLOT_COUNT:usize = 10;
+// An MD5 checksum followed by the slot's data.
+const SLOT_SIZE:usize = games::CHECKSUM_LENGTH + 0x100000;
+
+pub struct Sekiro;
 
-impl std::fmt::Display for SekiroConfig {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SekiroConfig Error")
+impl SaveGame for Sekiro {
+    fn id(&self) -> &'static str {
+        return "Sekiro";
     }
-}
 
-pub fn new() -> SekiroConfig {
-    return SekiroConfig {
-        override_file_location: String::from(""),
-        save_slot: 0,
+    fn name(&self) -> &'static str {
+        return "Sekiro: Shadows Die Twice";
+    }
+
+    // e.g. %APPDATA%/Sekiro/[12345678]/S0000.sl2
+    fn save_folder(&self) -> SaveFolder {
+        return SaveFolder::AppData("Sekiro");
+    }
+
+    fn steam_app_id(&self) -> u32 {
+        return 814380;
+    }
+
+    fn file_names(&self) -> &'static [&'static str] {
+        return &["S0000.sl2"];
     }
-}
 
-pub fn parse(slot: usize, file_buffer: &Vec<u8>) -> u32 {
-    // The first "file" in BND4 starts at 0x40, but in this case it seems to be a metadata file.
-    // So we're skipping the first file and going to 0x60 which is the first character save slot.
-    let meta_start = 0x40 + slot*0x20;
+    fn slot_count(&self) -> usize {
+        return SLOT_COUNT;
+    }
 
-    // When reading the 32 bytes (0x20), the data layout is as follows
-    // ID (4 bytes)
-    // Flag (4 bytes)
-    // Data Length (4 Bytes)
-    // --unused-- (4 Bytes)
-    // Data Offset (4 Bytes)
-    // Name Offset (4 Bytes)
-    // --unused-- (4 Bytes)
-    // --unused-- (4 Bytes)
-    let data_length_vec = &file_buffer[meta_start+8..meta_start+12];
-    let mut data_length = [0; 4];
-    data_length.copy_from_slice(&data_length_vec[0..4]);
-    let data_length = u32::from_le_bytes(data_length);
-    let slot_offset_vec = &file_buffer[meta_start+16..meta_start+20];
-    let mut slot_offset = [0; 4];
-    slot_offset.copy_from_slice(&slot_offset_vec[0..4]);
-    let slot_offset = u32::from_le_bytes(slot_offset);
-    let slot_start = slot_offset as usize;
-    let slot_end = slot_start + data_length as usize;
+    fn read_slot(&self, slot: usize, file_buffer: &[u8]) -> Result<SlotInfo, SaveError> {
+        let slot_data = slot_data(slot, file_buffer)?;
 
-    let slot_data = &file_buffer[slot_start..slot_end];
+        let death_pointer = 0x33F60;
+        // Only the death counter is read from Sekiro slots, not the name, level or play time (see the
+        // slots table in the README), so a slot only counts as empty if the game has never written to it.
+        return Ok(SlotInfo {
+            slot,
+            name: None,
+            level: None,
+            play_time: None,
+            deaths: bytes::read_u32(slot_data, death_pointer)?,
+            fingerprint: games::fingerprint(slot_data),
+        });
+    }
 
-    let mut deaths_arr = [0; 4];
-    let death_pointer = 0x33F60;
-    //println!("Offset: {}", (current_pointer - starting_pointer));
-    deaths_arr.copy_from_slice(&slot_data[death_pointer..death_pointer+4]);
-    return u32::from_le_bytes(deaths_arr);
+    // Sekiro's slots aren't encrypted, so they look like save data as they are.
+    // Elden Ring's aren't either, the slot size tells the two apart.
+    fn detect(&self, file_buffer: &[u8]) -> bool {
+        let first_used = games::first_used_slot(SLOT_COUNT, |slot| {
+            self.read_slot(slot, file_buffer)?;
+            return slot_data(slot, file_buffer);
+        });
+        return first_used.is_some_and(|slot_data| slot_data.len() == SLOT_SIZE && games::looks_decrypted(slot_data));
+    }
 }
 
-pub fn get_save_location() -> Result<PathBuf, Box<dyn Error>> {
-    let mut save_location = dirs::data_dir().unwrap();
-    save_location.push(r"Sekiro");
+fn slot_data(slot: usize, file_buffer: &[u8]) -> Result<&[u8], SaveError> {
+    if slot >= SLOT_COUNT {
+        return Err(SaveError::SlotOutOfRange { slot, slot_count: SLOT_COUNT });
+    }
+    let container = Bnd4::parse(file_buffer)?;
+    let slot_data = container.data(container.find(&bnd4::user_data_name(slot))?);
+    if games::is_blank(slot_data) {
+        return Err(SaveError::EmptySlot { slot });
+    }
+    games::verify_checksum(slot, slot_data)?;
+    return Ok(slot_data);
+}
 
-    // The save files are within a child folder that is named with the users unique ID or username.
-    // e.g. %APPDATA%/Sekiro/[12345678]/S0000.sl2
-    // Since I haven't figured out if there is a way to get this ID, we just take the first child folder.
-    // TODO: Let the user view and select a user folder if there is more than one.
-    return match std::fs::read_dir(&save_location) {
-        Err(err) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            Err(Box::new(err))
-        },
-        Ok(paths) => {
-            for path in paths {
-                // Let's hope there is only one directory in here.
-                save_location.push(path.unwrap().path());
-                // TODO: Implement better checking for save file
-                save_location.push("S0000.sl2");
-                break;
-            }
-            Ok(save_location)
-        },
-    };
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+    use super::*;
+    use crate::bnd4;
+
+    fn slot(deaths: u32) -> Vec<u8> {
+        let mut slot = vec![0; SLOT_SIZE];
+        slot[0x33F60..0x33F64].copy_from_slice(&deaths.to_le_bytes());
+        let checksum = Md5::digest(&slot[games::CHECKSUM_LENGTH..]);
+        slot[..games::CHECKSUM_LENGTH].copy_from_slice(&checksum[..]);
+        return slot;
+    }
+
+    #[test]
+    fn empty_slots_are_left_out() {
+        let file_buffer = bnd4::build(&[vec![0; SLOT_SIZE], slot(55), vec![0; SLOT_SIZE]]);
+        games::tests::assert_empty_slots_left_out(&Sekiro, &file_buffer, &[0, 2], &[1]);
+        assert_eq!(Sekiro.read_slot(1, &file_buffer).unwrap().deaths, 55);
+    }
 }
@@ -1,86 +1,284 @@
-use std::io::prelude::*;
-use std::path::PathBuf;
+#![allow(clippy::needless_return)]
+
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::Duration;
+use crate::games::SlotInfo;
 
+mod args;
+mod atomicfile;
+mod autoslot;
+mod bnd4;
+mod bytes;
 mod config;
 mod games;
+mod profiles;
+mod savefile;
+mod statefile;
+mod steam;
 mod deaths;
+mod error;
 mod watcher;
 
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+const READ_ATTEMPTS: u32 = 5;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(250);
 
 fn main() {
     println!("Souls-Like Death Counter v{}", VERSION.unwrap_or("-unknown"));
 
-    let config = config::load_config().unwrap();
-    println!("Game Selected: {:?}", config.current_game);
+    let args = match args::parse() {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        },
+        Ok(a) => a,
+    };
 
-    // Not using a special thread shutdown communication channel here. Just praying it gets killed when the parent process ends.
-    // Gist: I don't know enough about threads.
-    thread::spawn(move || {
-        watcher::start(&config, callback).unwrap();
+    // Ctrl+C, closing the terminal or a service manager stopping us all come through here,
+    // so they shut down the same way as q + enter.
+    let (quit_tx, quit_rx) = crossbeam_channel::unbounded();
+    let signal_tx = quit_tx.clone();
+    if let Err(e) = ctrlc::set_handler(move || { let _ = signal_tx.send(()); }) {
+        println!("WARNING: Couldn't listen for Ctrl+C: {}", e);
+    }
+
+    let mut config = match config::load_config() {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        },
+        Ok(c) => c,
+    };
+    if let Some(profile) = args.profile {
+        config.pin_profile(&profile);
+    }
+    if config.wait_for_save && args.command == args::Command::Run && !config::has_save(&config) {
+        wait_for_save(&config, &quit_rx);
+    }
+    let save_location = match config::get_save_location(&mut config) {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        },
+        Ok(l) => l,
+    };
+    println!("Game Selected: {}", config.game().name());
+    println!("Save File: {}", save_location.display());
+
+    if args.command == args::Command::Slots {
+        print_slots(&config, &save_location);
+        return;
+    }
+
+    // Dropping stop_tx tells the watcher thread to finish up.
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+    let watcher_done = quit_tx.clone();
+    let watcher_thread = thread::spawn(move || {
+        let mut auto_slot = autoslot::AutoSlot::new();
+        let mut tracker = deaths::DeathTracker::new();
+        let outputs = config.outputs();
+        let game_id = config.game().id();
+        let game = config.game().name();
+        let sync = config.sync_writes;
+        tracker.subscribe(move |change| {
+            for output in &outputs {
+                deaths::save(output, change, game_id, game, sync);
+            }
+        });
+        if config.state_file != Path::new("") {
+            let state_file = config.state_file.clone();
+            tracker.subscribe(move |change| statefile::save(&state_file, change, game_id, game, sync));
+        }
+        tracker.subscribe(|change| match change.old {
+            None => println!("Deaths: {}", change.new),
+            Some(old) => println!("Deaths: {} -> {} ({:+})", old, change.new, change.delta),
+        });
+        let result = watcher::start(&config, save_location, &stop_rx, |config, location| {
+            if let Some(info) = callback(config, location, &mut auto_slot, &stop_rx) {
+                tracker.update(info, location);
+            }
+        });
+        // Wake main up when the watcher stops by itself.
+        let _ = watcher_done.send(());
+        return result.map(|_| tracker);
     });
 
     println!("q + enter to quit");
-    loop {
-        let mut buffer = String::new();
+    thread::spawn(move || {
         let stdin = std::io::stdin();
-        stdin.read_line(&mut buffer).unwrap();
-        let b: u8 = buffer.trim().as_bytes()[0];
-        match b as char {
-            'q' => {
-                break;
-            },
-            _ => (),
+        loop {
+            let mut buffer = String::new();
+            match stdin.read_line(&mut buffer) {
+                // No terminal to read from, e.g. started in the background. Ctrl+C or a signal still stops us.
+                Ok(0) | Err(_) => return,
+                Ok(_) if buffer.trim().starts_with('q') => {
+                    let _ = quit_tx.send(());
+                    return;
+                },
+                Ok(_) => (),
+            }
         }
+    });
+
+    let _ = quit_rx.recv();
+    drop(stop_tx);
+    let exit_code = match watcher_thread.join() {
+        Ok(Ok(tracker)) => {
+            println!("{}", tracker.summary());
+            0
+        },
+        Ok(Err(e)) => {
+            println!("ERROR: Watching the save file failed: {:?}", e);
+            1
+        },
+        Err(_) => 1,
+    };
+    println!("Stopped.");
+    std::process::exit(exit_code);
+}
+
+fn wait_for_save(config: &config::ConfigFile, quit: &crossbeam_channel::Receiver<()>) {
+    let roots = match config::wait_roots(config) {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
+        },
+        Ok(r) => r,
+    };
+    if roots.is_empty() {
+        return;
+    }
+    println!("No save file yet, waiting for the game to create one. Looking in:");
+    for root in &roots {
+        println!("  {}", root.display());
+    }
+    match watcher::wait_for(config, &roots, quit, || config::has_save(config)) {
+        Err(e) => {
+            println!("ERROR: Waiting for the save file failed: {:?}", e);
+            std::process::exit(1);
+        },
+        Ok(false) => {
+            println!("Stopped.");
+            std::process::exit(0);
+        },
+        Ok(true) => println!("Save file found!"),
     }
 }
 
-fn callback(config: &config::ConfigFile, save_file_location: &PathBuf) {
-    let mut file_buffer = Vec::new();
-    {
-        let mut save_file = match std::fs::File::open(save_file_location) {
-            Err(_) => {
-                println!("ERROR: Couldn't open save file");
-                return;
+// Returns the tracked slot, if the save could be read.
+// Gives up early if stop is disconnected, so a save that keeps looking half written doesn't hold up quitting.
+fn callback(
+    config: &config::ConfigFile,
+    save_file_location: &Path,
+    auto_slot: &mut autoslot::AutoSlot,
+    stop: &crossbeam_channel::Receiver<()>,
+) -> Option<SlotInfo> {
+    let game = config.game();
+    // Even once the file has settled it can be a torn read, e.g. the game paused part way through
+    // a write, so anything that looks like a half-written file is read again after a backoff.
+    let mut retry_delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    let (deaths, file_buffer) = loop {
+        let file_buffer = match savefile::read_when_stable(save_file_location, stop) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return None,
+            Err(e) => {
+                println!("ERROR: Couldn't read save file: {}", e);
+                return None;
             },
-            Ok(f) => f,
+            Ok(b) => b,
         };
-        match save_file.read_to_end(&mut file_buffer) {
-            Err(_) => {
-                println!("ERROR: Couldn't read save file");
-                return;
+
+        let deaths = match config.game_config().save_slot {
+            config::SaveSlot::Slot(save_slot) => game.read_slot(save_slot, &file_buffer),
+            config::SaveSlot::Mode(config::SlotMode::Auto) => {
+                match game.list_slots(&file_buffer) {
+                    Err(e) => Err(e),
+                    Ok(slots) => match auto_slot.update(&slots) {
+                        None => {
+                            println!("No characters found in the save file yet.");
+                            return None;
+                        },
+                        Some(slot) => Ok(slots.into_iter().find(|info| info.slot == slot).unwrap()),
+                    },
+                }
             },
-            Ok(_) => (),
+        };
+        match deaths {
+            Err(ref e) if e.is_partial_write() && attempt < READ_ATTEMPTS => {
+                println!("Save file looks half written ({}), reading it again.", e);
+                attempt += 1;
+                if !savefile::wait(stop, retry_delay) {
+                    return None;
+                }
+                retry_delay *= 2;
+            },
+            _ => break (deaths, file_buffer),
+        }
+    };
+    if let Err(error::SaveError::SlotOutOfRange { .. } | error::SaveError::EmptySlot { .. }) = deaths {
+        if let Ok(slots) = game.list_slots(&file_buffer) {
+            let slots: Vec<String> = slots.iter()
+                .map(|info| format!("{} ({} deaths)", info.slot, info.deaths))
+                .collect();
+            println!("Readable save slots: {}", slots.join(", "));
         }
     }
 
-    let deaths = match config.current_game  {
-        config::Game::Ds1 => {
-            let save_slot = config.ds1_config.save_slot;
-            games::ds1::parse(save_slot, &file_buffer)
-        },
-        config::Game::Dsr => {
-            let save_slot = config.dsr_config.save_slot;
-            games::dsr::parse(save_slot, &file_buffer)
-        },
-        config::Game::Ds2 => {
-            let save_slot = config.ds2_config.save_slot;
-            games::ds2::parse(save_slot, &file_buffer)
+    // On a bad read we leave the output file alone so it keeps showing the last good count.
+    return match deaths {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            None
         },
-        config::Game::Ds2Sotfs => {
-            println!("Game not supported yet.");
-            return;
+        Ok(info) => Some(info),
+    };
+}
+
+fn print_slots(config: &config::ConfigFile, save_file_location: &PathBuf) {
+    let file_buffer = match std::fs::read(save_file_location) {
+        Err(_) => {
+            println!("ERROR: Couldn't read save file");
+            std::process::exit(1);
         },
-        config::Game::Ds3 => {
-            let save_slot = config.ds3_config.save_slot;
-            games::ds3::parse(save_slot, &file_buffer)
+        Ok(b) => b,
+    };
+    let slots = match config.game().list_slots(&file_buffer) {
+        Err(e) => {
+            println!("ERROR: {}", e);
+            std::process::exit(1);
         },
-        config::Game::Sekiro => {
-            let save_slot = config.sekiro_config.save_slot;
-            games::sekiro::parse(save_slot, &file_buffer)
-        }
+        Ok(s) => s,
     };
 
-    deaths::save(&config.output_deaths_location, deaths, 0);
+    // Only the columns this game's slots give us, a column of "-" doesn't help anyone pick a slot.
+    let show_name = slots.iter().any(|info| info.name.is_some());
+    let show_level = slots.iter().any(|info| info.level.is_some());
+    let show_play_time = slots.iter().any(|info| info.play_time.is_some());
+    let unknown = || String::from("-");
+    let mut header = format!("{:<6}", "Slot");
+    if show_name {
+        header.push_str(&format!("{:<18}", "Name"));
+    }
+    if show_level {
+        header.push_str(&format!("{:<8}", "Level"));
+    }
+    if show_play_time {
+        header.push_str(&format!("{:<12}", "Play Time"));
+    }
+    println!("{}Deaths", header);
+    for info in slots {
+        let mut row = format!("{:<6}", info.slot);
+        if show_name {
+            row.push_str(&format!("{:<18}", info.name.unwrap_or_else(unknown)));
+        }
+        if show_level {
+            row.push_str(&format!("{:<8}", info.level.map(|level| level.to_string()).unwrap_or_else(unknown)));
+        }
+        if show_play_time {
+            row.push_str(&format!("{:<12}", info.play_time.map(deaths::format_play_time).unwrap_or_else(unknown)));
+        }
+        println!("{}{}", row, info.deaths);
+    }
+    println!("Set save_slot for {} in config.toml to the slot you want to track.", config.current_game);
 }
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::games::SaveGame;
+
+// A save folder for one account. The save files are within a child folder that is named with
+// the users unique ID or username, e.g. ~/Documents/NBGI/DARK SOULS REMASTERED/[12345678]/DRAKS0005.sl2
+pub struct Profile {
+    pub id: String,
+    pub save_location: PathBuf,
+    // None when the folder exists but the game hasn't written a save into it yet.
+    pub modified: Option<SystemTime>,
+    pub character_names: Vec<String>,
+}
+
+// Finds a save file directly in the folder, or one per user folder inside it.
+pub fn find(game: &dyn SaveGame, folder: &Path) -> Vec<Profile> {
+    if let Some(profile) = read_profile(game, folder) {
+        if profile.modified.is_some() {
+            return vec![profile];
+        }
+    }
+
+    let paths = match std::fs::read_dir(folder) {
+        Err(_) => return Vec::new(),
+        Ok(p) => p,
+    };
+    let mut profiles: Vec<Profile> = paths
+        .filter_map(|path| path.ok())
+        .map(|path| path.path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| read_profile(game, &path))
+        .collect();
+    profiles.sort_by(|a, b| a.id.cmp(&b.id));
+    return profiles;
+}
+
+// Picks the pinned profile if there is one, otherwise the most recently saved.
+pub fn choose(game: &dyn SaveGame, profiles: Vec<Profile>, pinned: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+    if profiles.is_empty() {
+        println!("ERROR: Save File Not Found.");
+        println!("Please open character creation first before starting this program.");
+        println!("If you have already created a character and see this error, something went wrong.");
+        return Err(format!("No save folder found for {}", game.name()).into());
+    }
+
+    if profiles.len() > 1 || pinned.is_some() {
+        println!("Profiles found:");
+        for profile in &profiles {
+            println!("  {}", describe(profile));
+        }
+    }
+
+    let chosen = match pinned {
+        Some(id) => profiles.into_iter().find(|profile| profile.id == id)
+            .ok_or_else(|| format!("Profile {} not found", id))?,
+        // None sorts before Some, so folders without a save are only picked when nothing else has one.
+        None => profiles.into_iter().max_by_key(|profile| profile.modified).unwrap(),
+    };
+    println!("Profile Selected: {}", chosen.id);
+    return Ok(chosen.save_location);
+}
+
+fn read_profile(game: &dyn SaveGame, folder: &Path) -> Option<Profile> {
+    let id = folder.file_name()?.to_string_lossy().to_string();
+    // Some games have more than one save in the folder (e.g. Seamless Co-op's copy), the newest one is being played.
+    let save_location = game.file_names().iter()
+        .map(|name| folder.join(name))
+        .filter(|path| path.is_file())
+        .max_by_key(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok());
+    let save_location = match save_location {
+        None => return Some(Profile {
+            id,
+            save_location: folder.join(game.file_names()[0]),
+            modified: None,
+            character_names: Vec::new(),
+        }),
+        Some(l) => l,
+    };
+    return Some(from_file(game, id, save_location));
+}
+
+// A profile for a save file we already know the location of.
+pub fn from_file(game: &dyn SaveGame, id: String, save_location: PathBuf) -> Profile {
+    let modified = std::fs::metadata(&save_location).and_then(|metadata| metadata.modified()).ok();
+    let character_names = match std::fs::read(&save_location) {
+        Err(_) => Vec::new(),
+        Ok(file_buffer) => game.list_slots(&file_buffer).unwrap_or_default().into_iter()
+            .filter_map(|info| info.name)
+            .collect(),
+    };
+    return Profile {
+        id,
+        save_location,
+        modified: Some(modified.unwrap_or(SystemTime::UNIX_EPOCH)),
+        character_names,
+    };
+}
+
+fn describe(profile: &Profile) -> String {
+    let modified = match profile.modified.map(|modified| modified.elapsed()) {
+        None => String::from("no save yet"),
+        Some(Err(_)) => String::from("saved just now"),
+        Some(Ok(age)) => format!("saved {} ago", format_age(age.as_secs())),
+    };
+    let mut description = format!("{} ({})", profile.id, modified);
+    if !profile.character_names.is_empty() {
+        description.push_str(&format!(" - {}", profile.character_names.join(", ")));
+    }
+    return description;
+}
+
+fn format_age(seconds: u64) -> String {
+    return match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    };
+}
@@ -0,0 +1,47 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+// Games write their save in several chunks, so the first change we're told about is often
+// the start of a write rather than the end of it. Waiting until the size and modified time
+// stop changing means we usually read the file once it's whole.
+
+const SETTLE_DELAY: Duration = Duration::from_millis(100);
+// Don't wait forever on a file that keeps changing, the checksums will catch a torn read.
+const SETTLE_LIMIT: Duration = Duration::from_secs(3);
+
+// Gives up with an Interrupted error if stop is disconnected while we're waiting.
+pub fn read_when_stable(path: &Path, stop: &Receiver<()>) -> io::Result<Vec<u8>> {
+    let started = Instant::now();
+    let mut last = stamp(path)?;
+    loop {
+        if !wait(stop, SETTLE_DELAY) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "stopped while waiting for the save to settle"));
+        }
+        let current = stamp(path)?;
+        let settled = current == last;
+        last = current;
+        let waited_too_long = started.elapsed() >= SETTLE_LIMIT;
+        if !settled && !waited_too_long {
+            continue;
+        }
+
+        let file_buffer = std::fs::read(path)?;
+        // Changed again while we were reading it, go back to waiting.
+        if stamp(path)? != last && !waited_too_long {
+            continue;
+        }
+        return Ok(file_buffer);
+    }
+}
+
+// Sleeps for delay, unless we're told to stop first. Returns false if we were.
+pub fn wait(stop: &Receiver<()>, delay: Duration) -> bool {
+    return matches!(stop.recv_timeout(delay), Err(RecvTimeoutError::Timeout));
+}
+
+fn stamp(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let metadata = std::fs::metadata(path)?;
+    return Ok((metadata.len(), metadata.modified().ok()));
+}
@@ -0,0 +1,120 @@
+use std::path::Path;
+use serde::Serialize;
+use crate::atomicfile;
+use crate::atomicfile::SyncMode;
+use crate::deaths;
+use crate::deaths::DeathChange;
+
+// A JSON file describing everything the counter knows, for scripts that want more than the
+// number in deaths.txt. Scripts rely on these fields, so they're documented in the README and
+// SCHEMA_VERSION goes up whenever one is renamed, removed or changes meaning. Adding a field
+// doesn't need a new version.
+//
+// The json output format writes the same fields, along with its filled in template.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+pub struct State<'a> {
+    schema_version: u32,
+    game_id: &'a str,
+    game: &'a str,
+    save_file: &'a Path,
+    slot: usize,
+    character: Option<&'a str>,
+    play_time_seconds: Option<u64>,
+    deaths: u32,
+    // Can be negative, e.g. after switching to a character with fewer deaths.
+    change: i64,
+    session_start_deaths: u32,
+    session_deaths: u32,
+    // Seconds since 1970.
+    last_change: u64,
+}
+
+impl<'a> State<'a> {
+    pub fn new(change: &'a DeathChange, game_id: &'a str, game: &'a str) -> State<'a> {
+        return State {
+            schema_version: SCHEMA_VERSION,
+            game_id,
+            game,
+            save_file: &change.save_file,
+            slot: change.slot.slot,
+            character: change.slot.name.as_deref(),
+            play_time_seconds: change.slot.play_time.map(|play_time| play_time.as_secs()),
+            deaths: change.new,
+            change: change.delta,
+            session_start_deaths: change.session_start,
+            session_deaths: change.session_deaths,
+            last_change: deaths::unix_seconds(change.timestamp),
+        };
+    }
+}
+
+pub fn save(path: &Path, change: &DeathChange, game_id: &str, game: &str, sync: SyncMode) {
+    let state = State::new(change, game_id, game);
+    let contents = match serde_json::to_string_pretty(&state) {
+        Err(e) => {
+            println!("Couldn't write to {}: {}", path.display(), e);
+            return;
+        },
+        Ok(contents) => contents,
+    };
+    if let Err(e) = atomicfile::write(path, contents.as_bytes(), sync) {
+        println!("Couldn't write to {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::games::SlotInfo;
+    use super::*;
+
+    // The fields documented in the README. Renaming or removing one needs SCHEMA_VERSION to go up.
+    const SCHEMA_FIELDS: &[&str] = &[
+        "schema_version", "game_id", "game", "save_file", "slot", "character", "play_time_seconds",
+        "deaths", "change", "session_start_deaths", "session_deaths", "last_change",
+    ];
+
+    #[test]
+    fn schema_is_stable() {
+        let change = DeathChange {
+            old: Some(12),
+            new: 15,
+            delta: 3,
+            timestamp: UNIX_EPOCH + Duration::from_secs(1792321493),
+            session_start: 12,
+            session_deaths: 3,
+            slot: SlotInfo {
+                slot: 0,
+                name: Some(String::from("Tarnished")),
+                level: Some(80),
+                play_time: Some(Duration::from_secs(3661)),
+                deaths: 15,
+                fingerprint: 0,
+            },
+            save_file: PathBuf::from("ER0000.sl2"),
+        };
+        let json = serde_json::to_value(State::new(&change, "EldenRing", "ELDEN RING")).unwrap();
+        let fields: Vec<&str> = json.as_object().unwrap().keys().map(|key| key.as_str()).collect();
+        let mut expected = SCHEMA_FIELDS.to_vec();
+        expected.sort_unstable();
+        assert_eq!(fields, expected);
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json, serde_json::json!({
+            "schema_version": 1,
+            "game_id": "EldenRing",
+            "game": "ELDEN RING",
+            "save_file": "ER0000.sl2",
+            "slot": 0,
+            "character": "Tarnished",
+            "play_time_seconds": 3661,
+            "deaths": 15,
+            "change": 3,
+            "session_start_deaths": 12,
+            "session_deaths": 3,
+            "last_change": 1792321493u64,
+        }));
+    }
+}
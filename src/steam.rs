@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+
+// Where Steam keeps itself on Linux, the native install, the symlinks it makes, and the Flatpak.
+const STEAM_ROOTS: &[&str] = &[
+    ".steam/steam",
+    ".steam/root",
+    ".local/share/Steam",
+    ".var/app/com.valvesoftware.Steam/.local/share/Steam",
+    ".var/app/com.valvesoftware.Steam/data/Steam",
+];
+
+// Every Steam library folder we can find, the Steam install itself is always one of them.
+pub fn library_folders() -> Vec<PathBuf> {
+    let home = match dirs::home_dir() {
+        None => return Vec::new(),
+        Some(h) => h,
+    };
+
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for root in STEAM_ROOTS.iter().map(|root| home.join(root)) {
+        if !root.is_dir() {
+            continue;
+        }
+        push_unique(&mut libraries, root.clone());
+        for vdf in &["steamapps/libraryfolders.vdf", "config/libraryfolders.vdf"] {
+            if let Ok(contents) = std::fs::read_to_string(root.join(vdf)) {
+                for library in parse_library_folders(&contents) {
+                    push_unique(&mut libraries, library);
+                }
+            }
+        }
+    }
+    return libraries;
+}
+
+// The wine prefix Proton made for the game in each library, only the ones that exist.
+// e.g. ~/.steam/steam/steamapps/compatdata/374320/pfx
+pub fn proton_prefixes(app_id: u32) -> Vec<PathBuf> {
+    return library_folders().iter()
+        .map(|library| library.join("steamapps/compatdata").join(app_id.to_string()).join("pfx"))
+        .filter(|prefix| prefix.is_dir())
+        .collect();
+}
+
+// The user folders inside a wine prefix. Proton always uses steamuser, plain wine uses the login name.
+pub fn prefix_users(prefix: &Path) -> Vec<PathBuf> {
+    let users = prefix.join("drive_c/users");
+    let paths = match std::fs::read_dir(&users) {
+        Err(_) => return Vec::new(),
+        Ok(p) => p,
+    };
+    let mut user_folders: Vec<PathBuf> = paths
+        .filter_map(|path| path.ok())
+        .map(|path| path.path())
+        .filter(|path| path.is_dir() && !path.ends_with("Public"))
+        .collect();
+    user_folders.sort();
+    return user_folders;
+}
+
+// libraryfolders.vdf comes in two flavours.
+// Old: "LibraryFolders" { "1" "/path/to/library" }
+// New: "libraryfolders" { "0" { "path" "/path/to/library" "apps" { "374320" "123456" } } }
+// Numbered keys also show up in the new flavour's apps lists, so an old style entry only
+// counts directly under the root object and a path only directly under a library.
+fn parse_library_folders(contents: &str) -> Vec<PathBuf> {
+    let tokens = tokenize(contents);
+    let mut libraries = Vec::new();
+    let mut depth: usize = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        match (&tokens[i], tokens.get(i + 1)) {
+            (Token::Open, _) => {
+                depth += 1;
+                i += 1;
+            },
+            (Token::Close, _) => {
+                depth = depth.saturating_sub(1);
+                i += 1;
+            },
+            (Token::Text(key), Some(Token::Text(value))) => {
+                let is_old_entry = depth == 1 && !key.is_empty() && key.chars().all(|c| c.is_ascii_digit());
+                let is_path = depth == 2 && key.eq_ignore_ascii_case("path");
+                if is_old_entry || is_path {
+                    libraries.push(PathBuf::from(value));
+                }
+                i += 2;
+            },
+            _ => i += 1,
+        }
+    }
+    return libraries;
+}
+
+enum Token {
+    Text(String),
+    Open,
+    Close,
+}
+
+fn tokenize(contents: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                text.push(escaped);
+                            }
+                        },
+                        _ => text.push(c),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            },
+            _ => (),
+        }
+    }
+    return tokens;
+}
+
+fn push_unique(paths: &mut Vec<PathBuf>, path: PathBuf) {
+    // ~/.steam/steam is usually a symlink to one of the other roots.
+    let path = path.canonicalize().unwrap_or(path);
+    if !paths.contains(&path) {
+        paths.push(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_old_library_folders() {
+        let vdf = r#"
+"LibraryFolders"
+{
+	"TimeNextStatsReport"		"1700000000"
+	"ContentStatsID"		"-1234"
+	"1"		"/mnt/games/SteamLibrary"
+	"2"		"/home/user/Other Library"
+}
+"#;
+        assert_eq!(parse_library_folders(vdf), vec![
+            PathBuf::from("/mnt/games/SteamLibrary"),
+            PathBuf::from("/home/user/Other Library"),
+        ]);
+    }
+
+    #[test]
+    fn reads_new_library_folders_without_apps() {
+        let vdf = r#"
+"libraryfolders"
+{
+	"0"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"label"		""
+		"contentid"		"1234567890"
+		"totalsize"		"0"
+		"apps"
+		{
+			"228980"		"123456"
+			"374320"		"25000000000"
+		}
+	}
+	"1"
+	{
+		"path"		"/mnt/games/SteamLibrary"
+		"apps"
+		{
+			"1245620"		"50000000000"
+		}
+	}
+}
+"#;
+        assert_eq!(parse_library_folders(vdf), vec![
+            PathBuf::from("/home/user/.local/share/Steam"),
+            PathBuf::from("/mnt/games/SteamLibrary"),
+        ]);
+    }
+}
@@ -1,45 +1,187 @@
-use notify::{RecommendedWatcher, Watcher, RecursiveMode, DebouncedEvent, Result};
-use std::sync::mpsc::channel;
+use notify::{PollWatcher, RecommendedWatcher, Watcher, RecursiveMode, DebouncedEvent, Result};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use crossbeam_channel::{select, Receiver};
 use std::time::Duration;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::config;
-use crate::config::{ConfigFile};
+use crate::config::{ConfigFile, WatchMode};
 
-type CallbackOp = fn(config: &ConfigFile, save_location: &PathBuf);
+const WAIT_RECHECK: Duration = Duration::from_secs(5);
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
 
-pub fn start(config: &ConfigFile, callback_fn: CallbackOp) -> Result<()> {
-    let save_location = config::get_save_location(config);
+// Uses the operating system's file events where it can. Network shares and some FUSE mounts
+// don't send them, so the folders it can't get events for are checked on a timer instead.
+struct FolderWatcher {
+    tx: Sender<DebouncedEvent>,
+    poll_interval: Duration,
+    native: Option<RecommendedWatcher>,
+    poll: Option<PollWatcher>,
+}
+
+impl FolderWatcher {
+    fn new(config: &ConfigFile, tx: Sender<DebouncedEvent>, delay: Duration) -> FolderWatcher {
+        let native = match config.watcher {
+            WatchMode::Poll => None,
+            WatchMode::Native => match Watcher::new(tx.clone(), delay) {
+                Err(e) => {
+                    println!("WARNING: File system events aren't available ({:?}), checking for changes on a timer instead.", e);
+                    None
+                },
+                Ok(w) => Some(w),
+            },
+        };
+        return FolderWatcher {
+            tx,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            native,
+            poll: None,
+        };
+    }
 
+    fn watch(&mut self, folder: &Path) -> Result<()> {
+        // Polling is happy to watch a folder that isn't there, we want to know about it.
+        if !folder.is_dir() {
+            return Err(notify::Error::PathNotFound);
+        }
+        if let Some(native) = &mut self.native {
+            match native.watch(folder, RecursiveMode::NonRecursive) {
+                Ok(()) => return Ok(()),
+                Err(e) => println!(
+                    "WARNING: Couldn't get file system events for {} ({:?}), checking it every {}ms instead.",
+                    folder.display(), e, self.poll_interval.as_millis(),
+                ),
+            }
+        }
+        if self.poll.is_none() {
+            self.poll = Some(Watcher::new(self.tx.clone(), self.poll_interval)?);
+        }
+        return self.poll.as_mut().unwrap().watch(folder, RecursiveMode::NonRecursive);
+    }
+}
+
+// Runs until stop is disconnected, i.e. when the sender is dropped to shut down.
+// Returns an error if it stops for any other reason.
+pub fn start<F>(config: &ConfigFile, save_location: PathBuf, stop: &Receiver<()>, mut callback_fn: F) -> Result<()>
+where F: FnMut(&ConfigFile, &PathBuf) {
     let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(tx.clone(), Duration::from_secs(2))?;
-    match watcher.watch(&save_location, RecursiveMode::NonRecursive) {
-        Err(_) => {
-            println!("ERROR: Save File Not Found.");
-            println!("Please open character creation first before starting this program.");
-            println!("If you have already created a character and see this error, something went wrong.");
-            return Ok(());
-        },
-        Ok(_) => (),
+    let events = bridge(rx);
+    let mut watcher = FolderWatcher::new(config, tx, DEBOUNCE_DELAY);
+    // Games and Steam Cloud sometimes replace the save rather than writing to it, which would lose
+    // a watch on the file itself. Watching the folder it's in keeps working whatever happens to the file.
+    let folder = match save_location.parent() {
+        Some(folder) if folder != Path::new("") => folder.to_path_buf(),
+        _ => PathBuf::from("."),
     };
+    if watcher.watch(&folder).is_err() {
+        println!("ERROR: Save File Not Found.");
+        println!("Please open character creation first before starting this program.");
+        println!("If you have already created a character and see this error, something went wrong.");
+        return Err(notify::Error::PathNotFound);
+    }
 
     println!("Started Successfully");
-    let location = save_location.clone();
-    callback_fn(config, &location);
+    let save_names = config::save_file_names(config, &save_location);
+    // Moves to whichever of the saves was written last.
+    let mut location = save_location;
+    // The user ID folder can exist before the game has written a save into it, it'll be read once it's created.
+    if location.is_file() {
+        callback_fn(config, &location);
+    } else {
+        println!("Waiting for the game to create the save file.");
+    }
+    let is_save = |path: &PathBuf| path.file_name().is_some_and(|name| save_names.iter().any(|save| save == name));
     loop {
-        match rx.recv() {
-            Ok(event) => {
-                match event {
-                    DebouncedEvent::Write(_) => {
+        let event = select! {
+            recv(events) -> event => event,
+            recv(stop) -> _ => break,
+        };
+        match event {
+            Ok(event) => match event {
+                DebouncedEvent::Write(path) | DebouncedEvent::Create(path) if is_save(&path) => {
+                    location = path;
+                    callback_fn(config, &location);
+                },
+                DebouncedEvent::Rename(from, to) => {
+                    if is_save(&to) {
+                        location = to;
                         callback_fn(config, &location);
-                    },
-                    _ => (),
-                }
-            },
-            Err(e) => {
-                println!("ERROR: Watching file failed: {:?}", e);
-                break;
+                    } else if from.file_name() == location.file_name() {
+                        println!("Save file was moved away, waiting for it to come back.");
+                    }
+                },
+                DebouncedEvent::Remove(path) if path.file_name() == location.file_name() => {
+                    println!("Save file was removed, waiting for it to come back.");
+                },
+                // Events were missed, so check whether the save changed.
+                DebouncedEvent::Rescan if location.is_file() => {
+                    callback_fn(config, &location);
+                },
+                DebouncedEvent::Error(e, _) => println!("ERROR: Watching file failed: {}", e),
+                _ => (),
             },
+            // The notify watcher has gone away, so no more changes will ever come through.
+            Err(e) => return Err(notify::Error::Generic(format!("file events stopped ({})", e))),
         }
     }
     Ok(())
 }
+
+// Blocks until found() says a save has turned up in one of the roots. The events only tell us
+// when to check again, found() does the real searching.
+// Returns false if we were told to stop before it turned up.
+pub fn wait_for<F>(config: &ConfigFile, roots: &[PathBuf], stop: &Receiver<()>, mut found: F) -> Result<bool>
+where F: FnMut() -> bool {
+    let (tx, rx) = channel();
+    let events = bridge(rx);
+    let mut watcher = FolderWatcher::new(config, tx, Duration::from_secs(1));
+    let mut watched: Vec<PathBuf> = Vec::new();
+    while !found() {
+        for folder in roots.iter().flat_map(|root| folders_to_watch(root)) {
+            if !watched.contains(&folder) && watcher.watch(&folder).is_ok() {
+                watched.push(folder);
+            }
+        }
+        // Check again every so often anyway, in case a folder was made before we got a watch on it.
+        select! {
+            recv(events) -> event => if let Err(e) = event {
+                return Err(notify::Error::Generic(format!("file events stopped ({})", e)));
+            },
+            recv(stop) -> _ => return Ok(false),
+            default(WAIT_RECHECK) => (),
+        }
+    }
+    Ok(true)
+}
+
+// notify sends its events on a std channel, select! needs them on a crossbeam one.
+// The thread finishes by itself once the notify watcher is dropped.
+fn bridge(rx: std::sync::mpsc::Receiver<DebouncedEvent>) -> Receiver<DebouncedEvent> {
+    let (tx, events) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for event in rx {
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    return events;
+}
+
+// The game makes its save folder, then a user ID folder inside it, then the save. Until the folder
+// exists we watch the deepest part of the path that does, moving deeper as the folders appear.
+fn folders_to_watch(root: &Path) -> Vec<PathBuf> {
+    if !root.is_dir() {
+        return root.ancestors().skip(1)
+            .find(|folder| folder.is_dir())
+            .map(|folder| vec![folder.to_path_buf()])
+            .unwrap_or_default();
+    }
+    let mut folders = vec![root.to_path_buf()];
+    if let Ok(paths) = std::fs::read_dir(root) {
+        folders.extend(paths.filter_map(|path| path.ok())
+            .map(|path| path.path())
+            .filter(|path| path.is_dir()));
+    }
+    return folders;
+}